@@ -0,0 +1,482 @@
+use crate::Opcode;
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// Assembles Synacor assembly source into memory words.
+///
+/// Syntax, one statement per line, `;` starts a comment:
+/// - `name:` defines a label, `1234:` asserts the current address (as printed by `disasm`)
+/// - `op a, b, c` emits an instruction; operands are `r0..r7`, numbers (`42`, `0x2a`),
+///   characters (`'a'`, `'\n'`) or labels; `out "text"` emits one `out` per character
+/// - `.word a, b, ...` emits raw words, `.string "text"` emits one word per character
+/// - `.macro name p1, p2` ... `.endm` defines a macro, `name x, y` expands it
+pub fn assemble(source: &str) -> Result<Vec<u16>, AsmError> {
+    let mut lines = vec![];
+    for (i, line) in source.lines().enumerate() {
+        lines.push(tokenize(line, i + 1)?);
+    }
+
+    let mut assembler = Assembler {
+        words: vec![],
+        labels: HashMap::new(),
+        macros: HashMap::new(),
+    };
+    let mut lines = lines.into_iter();
+    while let Some(tokens) = lines.next() {
+        if let Some(first) = tokens.first()
+            && first.name() == ".macro"
+        {
+            let mut body = vec![];
+            loop {
+                let Some(line) = lines.next() else {
+                    return Err(first.error("unterminated .macro"));
+                };
+                if line.first().is_some_and(|t| t.name() == ".endm") {
+                    break;
+                }
+                body.push(line);
+            }
+            assembler.define_macro(&tokens, body)?;
+            continue;
+        }
+        assembler.statement(&tokens, 0)?;
+    }
+
+    let mut image = Vec::with_capacity(assembler.words.len());
+    for word in assembler.words {
+        image.push(match word {
+            Word::Literal(value) => value,
+            Word::Label(label) => match assembler.labels.get(label.name()) {
+                Some(address) => *address,
+                None => return Err(label.error(&format!("undefined label `{}`", label.name()))),
+            },
+        });
+    }
+    Ok(image)
+}
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u32),
+    Str(String),
+    Comma,
+    Colon,
+}
+
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    line: usize,
+    column: usize,
+}
+
+impl Spanned {
+    fn error(&self, message: &str) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            message: message.to_string(),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match &self.token {
+            Token::Ident(name) => name,
+            _ => "",
+        }
+    }
+}
+
+enum Word {
+    Literal(u16),
+    Label(Spanned),
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Vec<Spanned>>,
+}
+
+struct Assembler {
+    words: Vec<Word>,
+    labels: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+}
+
+impl Assembler {
+    fn define_macro(
+        &mut self,
+        tokens: &[Spanned],
+        body: Vec<Vec<Spanned>>,
+    ) -> Result<(), AsmError> {
+        let Some(name) = tokens.get(1).filter(|t| matches!(t.token, Token::Ident(_))) else {
+            return Err(tokens[0].error("expected macro name after .macro"));
+        };
+        let mut params = vec![];
+        for param in split_operands(&tokens[2..], &tokens[0])? {
+            match param.token {
+                Token::Ident(_) => params.push(param.name().to_string()),
+                _ => return Err(param.error("expected macro parameter name")),
+            }
+        }
+        if self.macros.contains_key(name.name()) || Opcode::from_name(name.name()).is_some() {
+            return Err(name.error(&format!("`{}` is already defined", name.name())));
+        }
+        self.macros
+            .insert(name.name().to_string(), Macro { params, body });
+        Ok(())
+    }
+
+    fn statement(&mut self, mut tokens: &[Spanned], depth: usize) -> Result<(), AsmError> {
+        while tokens.len() >= 2 && tokens[1].token == Token::Colon {
+            let address = self.words.len();
+            match &tokens[0].token {
+                Token::Ident(name) => {
+                    if self.labels.insert(name.clone(), address as u16).is_some() {
+                        return Err(tokens[0].error(&format!("label `{name}` is already defined")));
+                    }
+                }
+                Token::Number(expected) => {
+                    if *expected as usize != address {
+                        return Err(tokens[0].error(&format!(
+                            "address mismatch: expected {expected}, at {address}"
+                        )));
+                    }
+                }
+                _ => return Err(tokens[0].error("expected label before ':'")),
+            }
+            tokens = &tokens[2..];
+        }
+        let Some(first) = tokens.first() else {
+            return Ok(());
+        };
+        let Token::Ident(name) = &first.token else {
+            return Err(first.error("expected instruction, directive or label"));
+        };
+        let operands = split_operands(&tokens[1..], first)?;
+
+        match name.as_str() {
+            ".word" => {
+                for operand in operands {
+                    let word = self.operand(operand, 65535)?;
+                    self.words.push(word);
+                }
+            }
+            ".string" => {
+                for operand in operands {
+                    match &operand.token {
+                        Token::Str(s) => self
+                            .words
+                            .extend(s.chars().map(|c| Word::Literal(c as u16))),
+                        _ => return Err(operand.error("expected string literal")),
+                    }
+                }
+            }
+            ".macro" | ".endm" => return Err(first.error(&format!("unexpected {name}"))),
+            _ => {
+                if let Some(opcode) = Opcode::from_name(name) {
+                    self.instruction(opcode, first, &operands)?;
+                } else if self.macros.contains_key(name) {
+                    self.expand_macro(first, &operands, depth)?;
+                } else {
+                    return Err(first.error(&format!("unknown instruction `{name}`")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(
+        &mut self,
+        opcode: Opcode,
+        first: &Spanned,
+        operands: &[&Spanned],
+    ) -> Result<(), AsmError> {
+        if operands.len() != opcode.args() {
+            return Err(first.error(&format!(
+                "`{}` expects {} operands, found {}",
+                opcode.name(),
+                opcode.args(),
+                operands.len()
+            )));
+        }
+        if let (
+            Opcode::Out,
+            [
+                Spanned {
+                    token: Token::Str(s),
+                    ..
+                },
+            ],
+        ) = (opcode, operands)
+        {
+            for c in s.chars() {
+                self.words.push(Word::Literal(opcode.code()));
+                self.words.push(Word::Literal(c as u16));
+            }
+            return Ok(());
+        }
+        self.words.push(Word::Literal(opcode.code()));
+        for operand in operands {
+            let word = self.operand(operand, 32775)?;
+            self.words.push(word);
+        }
+        Ok(())
+    }
+
+    fn expand_macro(
+        &mut self,
+        first: &Spanned,
+        args: &[&Spanned],
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(first.error("macro expansion is too deep"));
+        }
+        let m = &self.macros[first.name()];
+        if args.len() != m.params.len() {
+            return Err(first.error(&format!(
+                "macro `{}` expects {} arguments, found {}",
+                first.name(),
+                m.params.len(),
+                args.len()
+            )));
+        }
+        let mut expanded = vec![];
+        for line in &m.body {
+            let mut tokens = vec![];
+            for token in line {
+                match m.params.iter().position(|p| p == token.name()) {
+                    Some(i) => tokens.push(args[i].clone()),
+                    None => tokens.push(token.clone()),
+                }
+            }
+            expanded.push(tokens);
+        }
+        for tokens in expanded {
+            self.statement(&tokens, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn operand(&self, token: &Spanned, max: u32) -> Result<Word, AsmError> {
+        match &token.token {
+            Token::Number(n) if *n <= max => Ok(Word::Literal(*n as u16)),
+            Token::Number(n) => Err(token.error(&format!("value {n} is out of range"))),
+            Token::Ident(name) => match parse_register(name) {
+                Some(r) => Ok(Word::Literal(32768 + r)),
+                None => Ok(Word::Label(token.clone())),
+            },
+            _ => Err(token.error("expected register, number or label")),
+        }
+    }
+}
+
+fn parse_register(name: &str) -> Option<u16> {
+    let r = name.strip_prefix('r')?.parse::<u16>().ok()?;
+    if r < 8 && name.len() == 2 {
+        Some(r)
+    } else {
+        None
+    }
+}
+
+fn split_operands<'a>(
+    tokens: &'a [Spanned],
+    first: &Spanned,
+) -> Result<Vec<&'a Spanned>, AsmError> {
+    if tokens.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut operands = vec![];
+    for operand in tokens.split(|t| t.token == Token::Comma) {
+        match operand {
+            [] => return Err(first.error("empty operand")),
+            [token] => operands.push(token),
+            [_, second, ..] => return Err(second.error("expected ',' between operands")),
+        }
+    }
+    Ok(operands)
+}
+
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<Spanned>, AsmError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        let error = |message: &str| AsmError {
+            line: line_number,
+            column,
+            message: message.to_string(),
+        };
+        let token = match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ',' => {
+                i += 1;
+                Token::Comma
+            }
+            ':' => {
+                i += 1;
+                Token::Colon
+            }
+            '0'..='9' => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let parsed = match text.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => text.parse::<u32>(),
+                };
+                match parsed {
+                    Ok(n) if n <= 65535 => Token::Number(n),
+                    _ => return Err(error(&format!("invalid number `{text}`"))),
+                }
+            }
+            '\'' => {
+                i += 1;
+                let (c, next) =
+                    read_char(&chars, i).ok_or_else(|| error("invalid character literal"))?;
+                if chars.get(next) != Some(&'\'') {
+                    return Err(error("unterminated character literal"));
+                }
+                i = next + 1;
+                Token::Number(c as u32)
+            }
+            '"' => {
+                i += 1;
+                let mut s = String::new();
+                loop {
+                    match chars.get(i) {
+                        None => return Err(error("unterminated string literal")),
+                        Some('"') => break,
+                        _ => {}
+                    }
+                    let (c, next) = read_char(&chars, i)
+                        .ok_or_else(|| error("invalid escape in string literal"))?;
+                    s.push(c);
+                    i = next;
+                }
+                i += 1;
+                Token::Str(s)
+            }
+            c if c.is_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            }
+            _ => return Err(error(&format!("unexpected character `{c}`"))),
+        };
+        tokens.push(Spanned {
+            token,
+            line: line_number,
+            column,
+        });
+    }
+    Ok(tokens)
+}
+
+/// Reads one possibly escaped character at `i`, returns it with the index of the next one.
+fn read_char(chars: &[char], i: usize) -> Option<(char, usize)> {
+    let c = *chars.get(i)?;
+    if c != '\\' {
+        return Some((c, i + 1));
+    }
+    let escaped = match *chars.get(i + 1)? {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        'x' => {
+            let hex: String = chars.get(i + 2..i + 4)?.iter().collect();
+            let value = u8::from_str_radix(&hex, 16).ok()?;
+            return Some((value as char, i + 4));
+        }
+        _ => return None,
+    };
+    Some((escaped, i + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn disassembly_of_challenge_bin_assembles_back_to_the_image() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        let listing = disassemble(&image, &Symbols::default()).unwrap();
+        assert_eq!(assemble(&listing).unwrap(), image);
+    }
+
+    #[test]
+    fn named_and_booted_listings_assemble_back_too() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        let symbols = Symbols::load("challenge.sym").unwrap();
+        assert_eq!(
+            assemble(&disassemble(&image, &symbols).unwrap()).unwrap(),
+            image
+        );
+
+        let vm = crate::snapshot::boot(&image, crate::snapshot::Until::Input, "").unwrap();
+        let booted = crate::snapshot::memory_image(&vm, image.len());
+        assert_eq!(
+            assemble(&disassemble(&booted, &symbols).unwrap()).unwrap(),
+            booted
+        );
+    }
+
+    #[test]
+    fn arch_spec_example() {
+        assert_eq!(
+            assemble("add r0, r1, 4\nout r0").unwrap(),
+            [9, 32768, 32769, 4, 19, 32768]
+        );
+    }
+
+    #[test]
+    fn macros_expand_with_their_arguments() {
+        let source = ".macro copy to, from\n  set to, from\n.endm\ncopy r2, 7\ncopy r0, r2";
+        assert_eq!(assemble(source).unwrap(), [1, 32770, 7, 1, 32768, 32770]);
+    }
+
+    #[test]
+    fn undefined_label_reports_its_position() {
+        let error = assemble("noop\n  jmp nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 7));
+        assert_eq!(error.to_string(), "2:7: undefined label `nowhere`");
+    }
+}
@@ -0,0 +1,269 @@
+use crate::Opcode;
+use crate::disasm::{decode, disassemble_range};
+use crate::discovery::CodeMap;
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution continues with the next block: plain fall-through or the return site of a `call`.
+    FallThrough,
+    /// Unconditional `jmp`.
+    Jump,
+    /// Taken branch of `jt`/`jf`.
+    Conditional,
+    Call,
+    /// From a block ending in `ret` to the return site of every direct caller.
+    Return,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub to: usize,
+}
+
+pub struct BasicBlock {
+    pub start: usize,
+    /// Address right after the last instruction of the block.
+    pub end: usize,
+    /// Address of the last instruction of the block.
+    pub last: usize,
+    pub edges: Vec<Edge>,
+}
+
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+impl Cfg {
+    /// Splits the discovered code into basic blocks and connects them.
+    pub fn build(data: &[u16], code_map: &CodeMap) -> Cfg {
+        let mut leaders: BTreeSet<usize> = code_map.targets.clone();
+        for (pointer, opcode) in instructions(data, code_map) {
+            if ends_block(opcode) {
+                leaders.insert(pointer + 1 + opcode.args());
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for (pointer, opcode) in instructions(data, code_map) {
+            if let Some(mut block) =
+                current.take_if(|b| b.end != pointer || leaders.contains(&pointer))
+            {
+                if block.end == pointer {
+                    block.edges.push(Edge {
+                        kind: EdgeKind::FallThrough,
+                        to: pointer,
+                    });
+                }
+                blocks.insert(block.start, block);
+            }
+            let end = pointer + 1 + opcode.args();
+            let block = current.get_or_insert(BasicBlock {
+                start: pointer,
+                end,
+                last: pointer,
+                edges: vec![],
+            });
+            block.end = end;
+            block.last = pointer;
+            if !ends_block(opcode) {
+                continue;
+            }
+
+            let target = match opcode {
+                Opcode::Jmp | Opcode::Call => Some(data[pointer + 1]),
+                Opcode::Jt | Opcode::Jf => Some(data[pointer + 2]),
+                _ => None,
+            };
+            let target = match target {
+                Some(target) if target >= 32768 => code_map.indirect[&pointer].map(|t| t as u16),
+                target => target,
+            };
+            let kind = match opcode {
+                Opcode::Jmp => EdgeKind::Jump,
+                Opcode::Call => EdgeKind::Call,
+                _ => EdgeKind::Conditional,
+            };
+            if let Some(target) = target {
+                block.edges.push(Edge {
+                    kind,
+                    to: target as usize,
+                });
+            }
+            if matches!(opcode, Opcode::Jt | Opcode::Jf | Opcode::Call)
+                && code_map.is_instruction(end)
+            {
+                block.edges.push(Edge {
+                    kind: EdgeKind::FallThrough,
+                    to: end,
+                });
+            }
+            let block = current.take().unwrap();
+            blocks.insert(block.start, block);
+        }
+        if let Some(block) = current {
+            blocks.insert(block.start, block);
+        }
+
+        let mut cfg = Cfg { blocks };
+        cfg.add_return_edges(data);
+        cfg
+    }
+
+    fn add_return_edges(&mut self, data: &[u16]) {
+        let mut return_sites: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for block in self.blocks.values() {
+            if let Some(call) = block.edges.iter().find(|e| e.kind == EdgeKind::Call) {
+                return_sites.entry(call.to).or_default().push(block.end);
+            }
+        }
+        for (function, sites) in return_sites {
+            for start in self.function_blocks(function) {
+                let block = self.blocks.get_mut(&start).unwrap();
+                if data[block.last] == Opcode::Ret.code() {
+                    for site in &sites {
+                        block.edges.push(Edge {
+                            kind: EdgeKind::Return,
+                            to: *site,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blocks reachable from `entry` without entering callees or following returns.
+    pub fn function_blocks(&self, entry: usize) -> BTreeSet<usize> {
+        let mut visited = BTreeSet::new();
+        let mut queue = vec![entry];
+        while let Some(start) = queue.pop() {
+            let Some(block) = self.blocks.get(&start) else {
+                continue;
+            };
+            if !visited.insert(start) {
+                continue;
+            }
+            for edge in &block.edges {
+                if !matches!(edge.kind, EdgeKind::Call | EdgeKind::Return) {
+                    queue.push(edge.to);
+                }
+            }
+        }
+        visited
+    }
+
+    /// Renders the given blocks, or the whole program when `only` is `None`, as a Graphviz digraph.
+    pub fn to_dot(
+        &self,
+        data: &[u16],
+        code_map: &CodeMap,
+        symbols: &Symbols,
+        name: &str,
+        only: Option<&BTreeSet<usize>>,
+    ) -> Result<String, std::fmt::Error> {
+        let mut writer = String::new();
+        writeln!(writer, "digraph \"{name}\" {{")?;
+        writeln!(writer, "  node [shape=box, fontname=monospace];")?;
+        let included = |start: &usize| only.is_none_or(|only| only.contains(start));
+        for block in self.blocks.values().filter(|b| included(&b.start)) {
+            let mut listing = String::new();
+            disassemble_range(
+                data,
+                block.start,
+                block.end,
+                code_map,
+                symbols,
+                &mut listing,
+            )?;
+            let label = listing
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\l");
+            writeln!(writer, "  b{} [label=\"{label}\"];", block.start)?;
+        }
+        for block in self.blocks.values().filter(|b| included(&b.start)) {
+            for edge in &block.edges {
+                let style = match edge.kind {
+                    EdgeKind::FallThrough => "",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Conditional => " [color=blue]",
+                    EdgeKind::Call => " [style=dashed, color=darkgreen]",
+                    EdgeKind::Return => " [style=dotted, color=gray]",
+                };
+                if only.is_some() && matches!(edge.kind, EdgeKind::Call | EdgeKind::Return) {
+                    continue;
+                }
+                writeln!(writer, "  b{} -> b{}{style};", block.start, edge.to)?;
+            }
+        }
+        writeln!(writer, "}}")?;
+        Ok(writer)
+    }
+}
+
+fn ends_block(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Halt | Opcode::Ret | Opcode::Jmp | Opcode::Jt | Opcode::Jf | Opcode::Call
+    )
+}
+
+fn instructions<'a>(
+    data: &'a [u16],
+    code_map: &'a CodeMap,
+) -> impl Iterator<Item = (usize, Opcode)> + 'a {
+    (0..data.len())
+        .filter(|p| code_map.is_instruction(*p))
+        .filter_map(|p| decode(data, p, data.len()).map(|opcode| (p, opcode)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::discovery::discover;
+
+    #[test]
+    fn branches_and_calls_split_blocks_and_returns_go_back_to_the_call_site() {
+        let data = assemble(
+            "
+            call sub
+            jf r0, skip
+            out 'x'
+        skip:
+            halt
+        sub:
+            set r0, 1
+            ret
+            ",
+        )
+        .unwrap();
+        let cfg = Cfg::build(&data, &discover(&data, &[0]));
+        let edges = |start: usize| cfg.blocks[&start].edges.clone();
+        let edge = |kind, to| Edge { kind, to };
+        assert_eq!(
+            cfg.blocks.keys().copied().collect::<Vec<_>>(),
+            vec![0, 2, 5, 7, 8]
+        );
+        assert_eq!(
+            edges(0),
+            vec![edge(EdgeKind::Call, 8), edge(EdgeKind::FallThrough, 2)]
+        );
+        assert_eq!(
+            edges(2),
+            vec![
+                edge(EdgeKind::Conditional, 7),
+                edge(EdgeKind::FallThrough, 5)
+            ]
+        );
+        assert_eq!(edges(5), vec![edge(EdgeKind::FallThrough, 7)]);
+        assert_eq!(edges(7), vec![]);
+        assert_eq!(edges(8), vec![edge(EdgeKind::Return, 2)]);
+        assert_eq!(cfg.function_blocks(0), BTreeSet::from([0, 2, 5, 7]));
+        assert_eq!(cfg.function_blocks(8), BTreeSet::from([8]));
+    }
+}
@@ -0,0 +1,456 @@
+use crate::vm::{Stop, Vm};
+use std::fmt;
+
+/// The coins found on the way to the monument, with the values their descriptions give away.
+pub const COINS: [Coin<'static>; 5] = [
+    Coin {
+        name: "red",
+        value: 2,
+    },
+    Coin {
+        name: "corroded",
+        value: 3,
+    },
+    Coin {
+        name: "shiny",
+        value: 5,
+    },
+    Coin {
+        name: "concave",
+        value: 7,
+    },
+    Coin {
+        name: "blue",
+        value: 9,
+    },
+];
+
+/// The equation carved into the monument.
+pub const MONUMENT: &str = "_ + _ * _^2 + _^3 - _ = 399";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Coin<'a> {
+    pub name: &'a str,
+    pub value: i64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Power,
+}
+
+impl Operator {
+    fn of(c: char) -> Option<Operator> {
+        match c {
+            '+' => Some(Operator::Add),
+            '-' => Some(Operator::Subtract),
+            '*' => Some(Operator::Multiply),
+            '^' => Some(Operator::Power),
+            _ => None,
+        }
+    }
+
+    /// Binding strength, higher binds tighter.
+    fn precedence(&self) -> u8 {
+        match self {
+            Operator::Add | Operator::Subtract => 1,
+            Operator::Multiply => 2,
+            Operator::Power => 3,
+        }
+    }
+
+    fn apply(&self, a: i64, b: i64) -> Option<i64> {
+        match self {
+            Operator::Add => a.checked_add(b),
+            Operator::Subtract => a.checked_sub(b),
+            Operator::Multiply => a.checked_mul(b),
+            Operator::Power => a.checked_pow(u32::try_from(b).ok()?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    /// The `n`th `_`, counting from the left.
+    Slot(usize),
+    Number(i64),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// The value with `values[n]` in slot `n`, or `None` on overflow.
+    fn evaluate(&self, values: &[i64]) -> Option<i64> {
+        match self {
+            Expr::Slot(n) => Some(values[*n]),
+            Expr::Number(number) => Some(*number),
+            Expr::Binary(operator, a, b) => {
+                operator.apply(a.evaluate(values)?, b.evaluate(values)?)
+            }
+        }
+    }
+}
+
+/// An equation over `_` slots, like the one on the monument: numbers, `_`, parentheses and
+/// `+ - * ^` with the usual precedence, `^` binding right to left.
+#[derive(Debug)]
+pub struct Equation {
+    left: Expr,
+    right: Expr,
+    pub slots: usize,
+}
+
+#[derive(Debug)]
+pub struct EquationError {
+    /// Character offset into the equation text.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for EquationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for EquationError {}
+
+impl Equation {
+    pub fn parse(text: &str) -> Result<Equation, EquationError> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            position: 0,
+            slots: 0,
+        };
+        let left = parser.expression(0)?;
+        parser.expect('=')?;
+        let right = parser.expression(0)?;
+        parser.skip_spaces();
+        if parser.position < parser.chars.len() {
+            return Err(parser.error("unexpected text after the equation"));
+        }
+        Ok(Equation {
+            left,
+            right,
+            slots: parser.slots,
+        })
+    }
+
+    /// Whether both sides are equal with `values` in the slots.
+    pub fn holds(&self, values: &[i64]) -> bool {
+        let left = self.left.evaluate(values);
+        left.is_some() && left == self.right.evaluate(values)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    slots: usize,
+}
+
+impl Parser {
+    /// Parses operators binding at least as tightly as `min_precedence`.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, EquationError> {
+        let mut left = self.operand()?;
+        loop {
+            self.skip_spaces();
+            let Some(operator) = self
+                .chars
+                .get(self.position)
+                .copied()
+                .and_then(Operator::of)
+            else {
+                return Ok(left);
+            };
+            if operator.precedence() < min_precedence {
+                return Ok(left);
+            }
+            self.position += 1;
+            let next = match operator {
+                Operator::Power => operator.precedence(),
+                _ => operator.precedence() + 1,
+            };
+            let right = self.expression(next)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr, EquationError> {
+        self.skip_spaces();
+        match self.chars.get(self.position) {
+            Some('_') => {
+                self.position += 1;
+                self.slots += 1;
+                Ok(Expr::Slot(self.slots - 1))
+            }
+            Some('(') => {
+                self.position += 1;
+                let inner = self.expression(0)?;
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.position;
+                while self
+                    .chars
+                    .get(self.position)
+                    .is_some_and(char::is_ascii_digit)
+                {
+                    self.position += 1;
+                }
+                let digits: String = self.chars[start..self.position].iter().collect();
+                let number = digits.parse().map_err(|_| self.error("number too large"))?;
+                Ok(Expr::Number(number))
+            }
+            _ => Err(self.error("expected `_`, a number or `(`")),
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), EquationError> {
+        self.skip_spaces();
+        if self.chars.get(self.position) != Some(&c) {
+            return Err(self.error(&format!("expected `{c}`")));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn skip_spaces(&mut self) {
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn error(&self, message: &str) -> EquationError {
+        EquationError {
+            position: self.position,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Every order of distinct `coins` that fills the slots of `equation` and makes it hold, in
+/// lexicographic order of the coins' positions in `coins`. Empty when there is none.
+pub fn solve<'a>(equation: &Equation, coins: &[Coin<'a>]) -> Vec<Vec<Coin<'a>>> {
+    let mut solutions = vec![];
+    let mut chosen = vec![];
+    let mut used = vec![false; coins.len()];
+    permute(equation, coins, &mut chosen, &mut used, &mut solutions);
+    solutions
+}
+
+fn permute<'a>(
+    equation: &Equation,
+    coins: &[Coin<'a>],
+    chosen: &mut Vec<Coin<'a>>,
+    used: &mut [bool],
+    solutions: &mut Vec<Vec<Coin<'a>>>,
+) {
+    if chosen.len() == equation.slots {
+        let values: Vec<i64> = chosen.iter().map(|coin| coin.value).collect();
+        if equation.holds(&values) {
+            solutions.push(chosen.clone());
+        }
+        return;
+    }
+    for i in 0..coins.len() {
+        if used[i] {
+            continue;
+        }
+        used[i] = true;
+        chosen.push(coins[i]);
+        permute(equation, coins, chosen, used, solutions);
+        chosen.pop();
+        used[i] = false;
+    }
+}
+
+/// The game commands that place `coins` in the monument's slots, left to right.
+pub fn commands(coins: &[Coin]) -> Vec<String> {
+    coins
+        .iter()
+        .map(|coin| format!("use {} coin", coin.name))
+        .collect()
+}
+
+/// Steps the game may take to answer one command.
+const MAX_COMMAND_STEPS: u64 = 10_000_000;
+
+/// Words the coin descriptions count dots with.
+const NUMBERS: [&str; 10] = [
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+];
+
+/// Shapes the coin descriptions use instead, by number of sides.
+const SHAPES: [(&str, i64); 7] = [
+    ("triangle", 3),
+    ("square", 4),
+    ("pentagon", 5),
+    ("hexagon", 6),
+    ("heptagon", 7),
+    ("octagon", 8),
+    ("nonagon", 9),
+];
+
+/// The equation in the output of `look` at the monument: the line with `_` slots and an `=`.
+pub fn parse_equation(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.contains('_') && line.contains('='))
+}
+
+/// The names of the coins listed in the output of `inv`, without the word `coin`.
+pub fn parse_inventory(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("- ")?.strip_suffix(" coin"))
+        .collect()
+}
+
+/// The value of a coin from its description, "It has two dots on one side" or "It has a
+/// triangle on one side".
+pub fn parse_coin_value(description: &str) -> Option<i64> {
+    let (_, rest) = description.split_once("It has ")?;
+    let (mark, _) = rest.split_once(" on one side")?;
+    let mark = mark
+        .strip_prefix("an ")
+        .or(mark.strip_prefix("a "))
+        .unwrap_or(mark);
+    if let Some(number) = mark.strip_suffix(" dots").or(mark.strip_suffix(" dot")) {
+        return NUMBERS
+            .iter()
+            .position(|n| *n == number)
+            .map(|i| i as i64 + 1);
+    }
+    SHAPES
+        .iter()
+        .find(|(shape, _)| *shape == mark)
+        .map(|(_, sides)| *sides)
+}
+
+/// Solves the monument in a game waiting for input in its room with the coins in the inventory:
+/// reads the equation and the coin values from the game's own output, then uses the coins in the
+/// order that solves it. Returns the commands it sent and what the game answered to the last one.
+pub fn solve_monument(vm: &mut Vm) -> Result<(Vec<String>, String), Box<dyn std::error::Error>> {
+    let look = send(vm, "look")?;
+    let text = parse_equation(&look).ok_or("no equation in the room description")?;
+    let equation = Equation::parse(text).map_err(|e| format!("equation at {e}"))?;
+
+    let inventory = send(vm, "inv")?;
+    let names = parse_inventory(&inventory);
+    let mut coins = vec![];
+    for name in &names {
+        let description = send(vm, &format!("look {name} coin"))?;
+        let value = parse_coin_value(&description)
+            .ok_or(format!("no value in the description of the {name} coin"))?;
+        coins.push(Coin { name, value });
+    }
+
+    let solutions = solve(&equation, &coins);
+    let solution = solutions
+        .first()
+        .ok_or(format!("no order of the coins solves `{text}`"))?;
+    let commands = commands(solution);
+    let mut answer = String::new();
+    for command in &commands {
+        answer = send(vm, command)?;
+    }
+    Ok((commands, answer))
+}
+
+/// Types `command` into the game and returns its output up to the next prompt.
+fn send(vm: &mut Vm, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+    vm.output.clear();
+    vm.push_input(command);
+    vm.push_input("\n");
+    match vm.run(MAX_COMMAND_STEPS)? {
+        Stop::NeedsInput => Ok(String::from_utf8_lossy(&vm.output).into_owned()),
+        stop => Err(format!("`{command}` stopped the game at {} ({stop:?})", vm.pc).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holds(text: &str) -> bool {
+        Equation::parse(text).unwrap().holds(&[])
+    }
+
+    #[test]
+    fn operators_bind_with_the_usual_precedence() {
+        assert!(holds("1 + 2 * 3 = 7"));
+        assert!(holds("(1 + 2) * 3 = 9"));
+        assert!(holds("2 * 3 ^ 2 = 18"));
+        assert!(holds("10 - 4 - 3 = 3"));
+        assert!(!holds("10 - 4 - 3 = 9"));
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert!(holds("2 ^ 3 ^ 2 = 512"));
+        assert!(!holds("2 ^ 3 ^ 2 = 64"));
+        assert!(holds("(2 ^ 3) ^ 2 = 64"));
+    }
+
+    #[test]
+    fn monument_takes_blue_red_shiny_concave_corroded() {
+        let solutions = solve(&Equation::parse(MONUMENT).unwrap(), &COINS);
+        let names: Vec<Vec<&str>> = solutions
+            .iter()
+            .map(|solution| solution.iter().map(|coin| coin.name).collect())
+            .collect();
+        assert_eq!(
+            names,
+            vec![vec!["blue", "red", "shiny", "concave", "corroded"]]
+        );
+        assert_eq!(
+            commands(&solutions[0]),
+            vec![
+                "use blue coin",
+                "use red coin",
+                "use shiny coin",
+                "use concave coin",
+                "use corroded coin"
+            ]
+        );
+    }
+
+    #[test]
+    fn equation_no_order_solves_has_no_solution() {
+        assert!(solve(&Equation::parse("_ + _ = 100").unwrap(), &COINS).is_empty());
+        // more slots than coins
+        assert!(solve(&Equation::parse("_+_+_+_+_+_ = 26").unwrap(), &COINS).is_empty());
+    }
+
+    #[test]
+    fn parse_errors_give_the_position() {
+        let error = Equation::parse("_ + * _ = 1").unwrap_err();
+        assert_eq!(error.to_string(), "4: expected `_`, a number or `(`");
+        let error = Equation::parse("_ = 1 )").unwrap_err();
+        assert_eq!(error.to_string(), "6: unexpected text after the equation");
+    }
+
+    #[test]
+    fn coin_values_come_from_dots_or_sides() {
+        let value = |text| parse_coin_value(text);
+        assert_eq!(
+            value("This coin is made of a red metal.  It has two dots on one side."),
+            Some(2)
+        );
+        assert_eq!(
+            value("This coin is somewhat corroded.  It has a triangle on one side."),
+            Some(3)
+        );
+        assert_eq!(value("It has an octagon on one side."), Some(8));
+        assert_eq!(value("It has a smiley on one side."), None);
+    }
+}
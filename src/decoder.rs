@@ -0,0 +1,361 @@
+use crate::Opcode;
+use crate::discovery::{WordKind, discover, entry_points, sweep_instructions};
+use crate::strings::MIN_LENGTH;
+use crate::symbols::Symbols;
+use crate::vm::{Stop, Vm};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Instructions before a call that are replayed to set up its arguments.
+const MAX_SETUP: usize = 16;
+
+/// Steps a single emulated call may take. Printing the longest message takes a few thousand.
+const MAX_CALL_STEPS: u64 = 100_000;
+
+/// Steps [`run_routine`] takes between looking at what the routine printed so far.
+const CHECK_STEPS: u64 = 32;
+
+pub struct Decoded {
+    /// Address of the record's length word.
+    pub record: usize,
+    /// Address of the literal `call` that passes the record, if there is one.
+    pub site: Option<usize>,
+    pub text: String,
+}
+
+/// A literal call as emulated by [`emulate_call`]: the arguments it passes and what it printed.
+struct Call {
+    site: usize,
+    routine: usize,
+    record: u16,
+    callback: u16,
+    key: u16,
+    text: String,
+}
+
+/// Finds the routine the game prints its text with and decodes every record with it.
+///
+/// Every literal `call` is emulated in a sub-VM: the straight-line code before it runs first, so
+/// that the arguments it loads (record address in r0, callback in r1, key in r2) are in place,
+/// and the call ends when execution returns after it. The decoder is the routine most often
+/// called with a record and a callback that prints the record as readable text, one character per
+/// word. It then runs on every other length word followed by that many data words, readable or
+/// not, with each callback and key its call sites pass. A candidate is a record when those print
+/// it as readable text of its length, all the same; garbage comes out differently under each key.
+///
+/// The records are encrypted at rest and the text routines only appear once the game has booted,
+/// so an image that was not booted has no such calls and gives an error.
+pub fn decode_messages(data: &[u16]) -> Result<(usize, Vec<Decoded>), String> {
+    // the calls below are emulated on this image, so it has to fit in memory
+    Vm::new(data).map_err(|e| e.to_string())?;
+    let code_map = discover(data, &entry_points(data));
+    let instructions = sweep_instructions(data, &code_map);
+
+    let is_record = |address: u16| {
+        let address = address as usize;
+        address < data.len()
+            && data[address] > 0
+            && address + 1 + (data[address] as usize) <= data.len()
+    };
+    let mut calls = vec![];
+    for (site, opcode) in &instructions {
+        if *opcode != Opcode::Call || data[site + 1] >= 32768 {
+            continue;
+        }
+        if let Some(call) = emulate_call(data, &instructions, *site)
+            && instructions.contains_key(&(call.callback as usize))
+        {
+            calls.push(call);
+        }
+    }
+    // the decoder prints one character per word of the record
+    let prints = |call: &Call| {
+        readable(&call.text)
+            && is_record(call.record)
+            && call.text.len() == data[call.record as usize] as usize
+    };
+    let mut by_routine: BTreeMap<usize, usize> = BTreeMap::new();
+    for call in calls.iter().filter(|call| prints(call)) {
+        *by_routine.entry(call.routine).or_default() += 1;
+    }
+    let Some((routine, _)) = by_routine.into_iter().max_by_key(|(_, count)| *count) else {
+        return Err(
+            "no call passes a record and a callback that prints it; boot the image first"
+                .to_string(),
+        );
+    };
+    calls.retain(|call| call.routine == routine);
+
+    // Wrappers pass a callback with the record they were given, so every call counts here, not
+    // only those that printed something.
+    let arguments: BTreeSet<(u16, u16)> =
+        calls.iter().map(|call| (call.callback, call.key)).collect();
+    // where the run of data words each address is in ends
+    let mut data_end = vec![0; data.len()];
+    for address in (0..data.len()).rev() {
+        data_end[address] = match code_map.kinds[address] {
+            WordKind::Data => data_end.get(address + 1).copied().unwrap_or(data.len()),
+            _ => address,
+        };
+    }
+    let mut decoded: Vec<Decoded> = calls
+        .into_iter()
+        .filter(prints)
+        .map(|call| Decoded {
+            record: call.record as usize,
+            site: Some(call.site),
+            text: call.text,
+        })
+        .collect();
+    // A record decoded at one address hides the candidates inside it.
+    let mut taken: Vec<(usize, usize)> = decoded
+        .iter()
+        .map(|d| (d.record, d.record + 1 + data[d.record] as usize))
+        .collect();
+    // Records may still be encrypted, so any length word followed by that many data words is a
+    // candidate, whatever the words look like.
+    for record in 0..data.len() {
+        let length = data[record] as usize;
+        if length < MIN_LENGTH
+            || record + 1 + length > data_end[record]
+            || taken
+                .iter()
+                .any(|(start, end)| (*start..*end).contains(&record))
+        {
+            continue;
+        }
+        // Words that are not text come out readable under several keys, each differently.
+        let texts: BTreeSet<String> = arguments
+            .iter()
+            .filter_map(|(callback, key)| {
+                run_routine(data, routine, record as u16, *callback, *key)
+                    .filter(|t| readable(t) && t.len() == length)
+            })
+            .collect();
+        if let [text] = Vec::from_iter(texts).as_slice() {
+            taken.push((record, record + 1 + length));
+            decoded.push(Decoded {
+                record,
+                site: None,
+                text: text.clone(),
+            });
+        }
+    }
+    decoded.sort_by_key(|d| (d.record, d.site));
+    Ok((routine, decoded))
+}
+
+fn readable(text: &str) -> bool {
+    text.chars().any(char::is_alphabetic) && text.bytes().all(|b| matches!(b, 32..=126 | 10))
+}
+
+/// Runs the call at `site` with the arguments its block sets up, noting them and what it printed.
+fn emulate_call(data: &[u16], instructions: &BTreeMap<usize, Opcode>, site: usize) -> Option<Call> {
+    let mut start = site;
+    for _ in 0..MAX_SETUP {
+        let Some((previous, opcode)) = instructions.range(..start).next_back() else {
+            break;
+        };
+        let straight = !matches!(
+            opcode,
+            Opcode::Halt
+                | Opcode::Ret
+                | Opcode::Jmp
+                | Opcode::Jt
+                | Opcode::Jf
+                | Opcode::Call
+                | Opcode::In
+        );
+        if !straight || previous + 1 + opcode.args() != start {
+            break;
+        }
+        start = *previous;
+    }
+
+    let mut vm = Vm::new(data).ok()?;
+    vm.pc = start;
+    if start < site {
+        vm.breakpoints.insert(site);
+        if vm.run(MAX_CALL_STEPS).ok()? != Stop::Breakpoint {
+            return None;
+        }
+        vm.breakpoints.clear();
+        vm.output.clear();
+    }
+    let (record, callback, key) = (vm.registers[0], vm.registers[1], vm.registers[2]);
+    vm.breakpoints.insert(site + 2);
+    match vm.run(MAX_CALL_STEPS) {
+        Ok(Stop::Breakpoint) => Some(Call {
+            site,
+            routine: data[site + 1] as usize,
+            record,
+            callback,
+            key,
+            text: String::from_utf8_lossy(&vm.output).into_owned(),
+        }),
+        _ => None,
+    }
+}
+
+/// Runs `routine` on its own with the given arguments until it returns, returning what it printed.
+/// Gives up as soon as it prints something that is not text.
+fn run_routine(
+    data: &[u16],
+    routine: usize,
+    record: u16,
+    callback: u16,
+    key: u16,
+) -> Option<String> {
+    let mut vm = Vm::new(data).ok()?;
+    vm.pc = routine;
+    vm.registers[..3].copy_from_slice(&[record, callback, key]);
+    while vm.steps < MAX_CALL_STEPS {
+        match vm.run(CHECK_STEPS).ok()? {
+            Stop::Halted => return Some(String::from_utf8_lossy(&vm.output).into_owned()),
+            Stop::StepLimit if vm.output.iter().all(|b| matches!(b, 32..=126 | 10)) => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub fn to_text(
+    routine: usize,
+    decoded: &[Decoded],
+    symbols: &Symbols,
+) -> Result<String, std::fmt::Error> {
+    let mut writer = String::new();
+    let calls = decoded.iter().filter(|d| d.site.is_some()).count();
+    writeln!(
+        writer,
+        "decoder: {} ({} records, {calls} passed by literal calls)",
+        symbols.label(routine),
+        decoded.len()
+    )?;
+    for d in decoded {
+        write!(
+            writer,
+            "{:>5}: \"{}\"",
+            symbols.label(d.record),
+            crate::disasm::escape_string(&d.text)
+        )?;
+        match d.site {
+            Some(site) => writeln!(writer, " (call at {})", symbols.label(site))?,
+            None => writeln!(writer)?,
+        }
+    }
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn is_plain_text(data: &[u16], record: usize) -> bool {
+        let length = data[record] as usize;
+        data[record + 1..record + 1 + length]
+            .iter()
+            .all(|w| matches!(w, 32..=126 | 10))
+    }
+
+    #[test]
+    fn records_encrypted_with_a_call_site_key_decode_without_being_passed() {
+        // `print` passes each word of the record in r0 to the callback in r1, `print_xored`
+        // prints r0 xor the key in r2. Both records are xored with 1000.
+        let data = assemble(
+            "
+            set r0, greeting
+            set r1, print_xored
+            set r2, 1000
+            call print
+            halt
+        print:
+            push r3
+            push r4
+            rmem r3, r0
+            set r4, r0
+        next:
+            jf r3, done
+            add r4, r4, 1
+            push r0
+            rmem r0, r4
+            call r1
+            pop r0
+            add r3, r3, 32767
+            jmp next
+        done:
+            pop r4
+            pop r3
+            ret
+        print_xored:
+            push r1
+            push r3
+            and r1, r0, r2
+            not r1, r1
+            or r3, r0, r2
+            and r0, r3, r1
+            out r0
+            pop r3
+            pop r1
+            ret
+        greeting:
+            .word 5, 928, 909, 900, 900, 903
+        hidden:
+            .word 12, 955, 909, 907, 922, 909, 924, 968, 924, 909, 912, 924, 994
+            ",
+        )
+        .unwrap();
+        let hidden = data.len() - 13;
+        assert!(!is_plain_text(&data, hidden));
+
+        let (routine, decoded) = decode_messages(&data).unwrap();
+        assert_eq!(routine, 12);
+        let decoded: Vec<(usize, Option<usize>, &str)> = decoded
+            .iter()
+            .map(|d| (d.record, d.site, d.text.as_str()))
+            .collect();
+        assert_eq!(
+            decoded,
+            vec![
+                (hidden - 6, Some(9), "Hello"),
+                (hidden, None, "Secret text\n")
+            ]
+        );
+    }
+
+    #[test]
+    fn booted_memory_decodes_the_records_literal_calls_pass_encrypted() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        let vm = crate::snapshot::boot(&image, crate::snapshot::Until::Input, "").unwrap();
+        let (routine, decoded) = decode_messages(&vm.mem).unwrap();
+        assert_eq!(routine, 1480);
+        let passed: Vec<&Decoded> = decoded.iter().filter(|d| d.site.is_some()).collect();
+        assert_eq!(passed.len(), 32);
+        for d in passed {
+            assert!(!is_plain_text(&vm.mem, d.record), "{} is plain", d.record);
+            assert!(readable(&d.text));
+        }
+        let text_at = |site: usize| {
+            let d = decoded.iter().find(|d| d.site == Some(site)).unwrap();
+            d.text.as_str()
+        };
+        assert_eq!(
+            text_at(1086),
+            "The self-test completion code is: BNCyODLfQkIl\n\n"
+        );
+        assert!(text_at(5617).ends_with(
+            "\"Miscalibration detected!  Aborting teleportation!\"\n\n\
+             Nothing else seems to happen.\n\n"
+        ));
+        // records nothing passes are printed as they are
+        let plain = decoded.iter().find(|d| d.record == 6090).unwrap();
+        assert_eq!((plain.site, plain.text.as_str()), (None, "Test string"));
+    }
+
+    #[test]
+    fn image_that_was_not_booted_is_an_error() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        assert!(decode_messages(&image).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::Opcode;
+use crate::discovery::{CodeMap, discover};
+use crate::symbols::Symbols;
+use std::fmt::Write;
+
+const WORDS_PER_LINE: usize = 8;
+
+/// Renders the whole image as an assembly listing, one `addr: op operands` line per instruction.
+///
+/// Code is separated from data by [`discover`] starting at address 0; everything it does not
+/// reach is emitted as `.word` data. Consecutive `out` instructions with literal ascii arguments
+/// are collapsed into one string literal (split after every newline and before jump targets).
+/// Every address in `symbols` gets a `name:` label line, and jump and call targets use the name.
+/// The listing is lossless: assembling it reproduces `data` exactly.
+pub fn disassemble(data: &[u16], symbols: &Symbols) -> Result<String, std::fmt::Error> {
+    let code_map = discover(data, &[0]);
+    let mut string_content = String::new();
+    disassemble_range(data, 0, data.len(), &code_map, symbols, &mut string_content)?;
+    Ok(string_content)
+}
+
+pub fn disassemble_range(
+    data: &[u16],
+    from: usize,
+    to: usize,
+    code_map: &CodeMap,
+    symbols: &Symbols,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    let instruction = |pointer: usize| {
+        if code_map.is_instruction(pointer) {
+            decode(data, pointer, to)
+        } else {
+            None
+        }
+    };
+    let mut pointer = from;
+    while pointer < to {
+        if let Some(symbol) = symbols.get(pointer) {
+            match &symbol.comment {
+                Some(comment) => writeln!(writer, "{}: ; {comment}", symbol.name)?,
+                None => writeln!(writer, "{}:", symbol.name)?,
+            }
+        }
+        let Some(opcode) = instruction(pointer) else {
+            let start = pointer;
+            let mut words = vec![];
+            while pointer < to
+                && words.len() < WORDS_PER_LINE
+                && instruction(pointer).is_none()
+                && (pointer == start || symbols.get(pointer).is_none())
+            {
+                words.push(data[pointer].to_string());
+                pointer += 1;
+            }
+            writeln!(writer, "{start:5}: .word {}", words.join(", "))?;
+            continue;
+        };
+
+        if opcode == Opcode::Out && is_char(data[pointer + 1]) {
+            let start = pointer;
+            let mut text = String::new();
+            while pointer < to
+                && instruction(pointer) == Some(Opcode::Out)
+                && is_char(data[pointer + 1])
+            {
+                let c = data[pointer + 1] as u8 as char;
+                text.push(c);
+                pointer += 2;
+                if c == '\n' || code_map.is_target(pointer) || symbols.get(pointer).is_some() {
+                    break;
+                }
+            }
+            writeln!(writer, "{start:5}: out \"{}\"", escape_string(&text))?;
+            continue;
+        }
+
+        write!(
+            writer,
+            "{pointer:5}: {}",
+            format_instruction(data, pointer, opcode, symbols)
+        )?;
+        match code_map.indirect.get(&pointer) {
+            Some(Some(target)) => write!(writer, " ; indirect -> {target}")?,
+            Some(None) => write!(writer, " ; indirect")?,
+            None => {}
+        }
+        writeln!(writer)?;
+        pointer += 1 + opcode.args();
+    }
+    Ok(())
+}
+
+/// Renders one instruction as `op a, b, c`, naming jump and call targets from `symbols`.
+pub fn format_instruction(
+    data: &[u16],
+    pointer: usize,
+    opcode: Opcode,
+    symbols: &Symbols,
+) -> String {
+    let target = match opcode {
+        Opcode::Jmp | Opcode::Call => Some(0),
+        Opcode::Jt | Opcode::Jf => Some(1),
+        _ => None,
+    };
+    let mut text = opcode.name().to_string();
+    for (i, arg) in data[pointer + 1..pointer + 1 + opcode.args()]
+        .iter()
+        .enumerate()
+    {
+        text.push_str(if i == 0 { " " } else { ", " });
+        match symbols.get(*arg as usize) {
+            Some(symbol) if target == Some(i) => text.push_str(&symbol.name),
+            _ => text.push_str(&to_operand(*arg)),
+        }
+    }
+    text
+}
+
+/// Returns the opcode at `pointer` if it is a complete instruction with valid operands that ends
+/// before `to`.
+pub fn decode(data: &[u16], pointer: usize, to: usize) -> Option<Opcode> {
+    let opcode = Opcode::try_of(data[pointer])?;
+    let end = pointer + 1 + opcode.args();
+    if end > to.min(data.len()) {
+        return None;
+    }
+    if data[pointer + 1..end].iter().any(|arg| *arg > 32775) {
+        return None;
+    }
+    Some(opcode)
+}
+
+pub fn to_operand(a: u16) -> String {
+    if a < 32768 {
+        a.to_string()
+    } else {
+        format!("r{}", a - 32768)
+    }
+}
+
+fn is_char(a: u16) -> bool {
+    a < 128
+}
+
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            ' '..='~' => escaped.push(c),
+            _ => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,239 @@
+use crate::Opcode;
+use crate::disasm::decode;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WordKind {
+    Data,
+    Instruction,
+    Operand,
+}
+
+/// Result of following control flow through an image: which words are code and which are data.
+pub struct CodeMap {
+    pub kinds: Vec<WordKind>,
+    /// Literal targets of `jmp`, `jt`, `jf` and `call`, plus the entry points.
+    pub targets: BTreeSet<usize>,
+    /// Literal `call` targets.
+    pub calls: BTreeSet<usize>,
+    /// Addresses of `call`/`jmp`/`jt`/`jf` instructions whose target is a register,
+    /// with the target when it could be resolved to a constant.
+    pub indirect: BTreeMap<usize, Option<usize>>,
+}
+
+impl CodeMap {
+    pub fn is_instruction(&self, address: usize) -> bool {
+        self.kinds.get(address) == Some(&WordKind::Instruction)
+    }
+
+    pub fn is_target(&self, address: usize) -> bool {
+        self.targets.contains(&address)
+    }
+}
+
+/// Separates code from data by following every path from `entries`.
+///
+/// Each reached instruction marks its operands, falls through unless it is `halt`, `ret` or `jmp`,
+/// and queues its literal jump or call target. Register targets are resolved when the register was
+/// loaded by a `set` with a literal earlier on the same path. Everything never reached stays
+/// [`WordKind::Data`].
+pub fn discover(data: &[u16], entries: &[usize]) -> CodeMap {
+    let mut code_map = CodeMap {
+        kinds: vec![WordKind::Data; data.len()],
+        targets: entries.iter().copied().collect(),
+        calls: BTreeSet::new(),
+        indirect: BTreeMap::new(),
+    };
+    let mut queue: Vec<(usize, [Option<u16>; 8])> =
+        entries.iter().map(|e| (*e, [None; 8])).collect();
+
+    while let Some((pointer, mut constants)) = queue.pop() {
+        if pointer >= data.len() || code_map.kinds[pointer] != WordKind::Data {
+            continue;
+        }
+        let Some(opcode) = decode(data, pointer, data.len()) else {
+            continue;
+        };
+        let end = pointer + 1 + opcode.args();
+        if code_map.kinds[pointer + 1..end]
+            .iter()
+            .any(|k| *k != WordKind::Data)
+        {
+            continue;
+        }
+        code_map.kinds[pointer] = WordKind::Instruction;
+        for kind in &mut code_map.kinds[pointer + 1..end] {
+            *kind = WordKind::Operand;
+        }
+
+        let target = match opcode {
+            Opcode::Jmp | Opcode::Call => Some(data[pointer + 1]),
+            Opcode::Jt | Opcode::Jf => Some(data[pointer + 2]),
+            _ => None,
+        };
+        let target = match target {
+            Some(target) if target >= 32768 => {
+                let resolved = constants[(target - 32768) as usize];
+                code_map
+                    .indirect
+                    .insert(pointer, resolved.map(|t| t as usize));
+                resolved
+            }
+            target => target,
+        };
+        if let Some(target) = target {
+            code_map.targets.insert(target as usize);
+            if opcode == Opcode::Call {
+                code_map.calls.insert(target as usize);
+            }
+            queue.push((target as usize, constants));
+        }
+
+        if let Some(r) = written_register(data, pointer, opcode) {
+            constants[r] = match opcode {
+                Opcode::Set if data[pointer + 2] < 32768 => Some(data[pointer + 2]),
+                _ => None,
+            };
+        }
+        if opcode == Opcode::Call {
+            // the callee may clobber any register
+            constants = [None; 8];
+        }
+        if !matches!(opcode, Opcode::Halt | Opcode::Ret | Opcode::Jmp) {
+            queue.push((end, constants));
+        }
+    }
+    code_map
+}
+
+fn written_register(data: &[u16], pointer: usize, opcode: Opcode) -> Option<usize> {
+    match opcode {
+        Opcode::Set
+        | Opcode::Pop
+        | Opcode::Eq
+        | Opcode::Gt
+        | Opcode::Add
+        | Opcode::Mult
+        | Opcode::Mod
+        | Opcode::And
+        | Opcode::Or
+        | Opcode::Not
+        | Opcode::Rmem
+        | Opcode::In => data[pointer + 1].checked_sub(32768).map(|r| r as usize),
+        _ => None,
+    }
+}
+
+/// Literal `call` targets found by decoding the whole image linearly. These include routines that
+/// [`discover`] cannot reach from address 0 because their callers are only entered through
+/// registers.
+pub fn sweep_calls(data: &[u16]) -> BTreeSet<usize> {
+    let mut calls = BTreeSet::new();
+    let mut pointer = 0;
+    while pointer < data.len() {
+        let Some(opcode) = decode(data, pointer, data.len()) else {
+            pointer += 1;
+            continue;
+        };
+        if opcode == Opcode::Call && (data[pointer + 1] as usize) < data.len() {
+            calls.insert(data[pointer + 1] as usize);
+        }
+        pointer += 1 + opcode.args();
+    }
+    calls
+}
+
+/// Where to start [`discover`]: address 0 and every literal `call` target from [`sweep_calls`].
+pub fn entry_points(data: &[u16]) -> Vec<usize> {
+    std::iter::once(0).chain(sweep_calls(data)).collect()
+}
+
+/// The instructions of `from..to` that `code_map` reached. Every other word is data: routines the
+/// game only enters through pointers or decrypts while it runs are left to the translations'
+/// interpreters instead of being decoded from whatever the image holds there.
+pub fn plan_instructions(
+    data: &[u16],
+    from: usize,
+    to: usize,
+    code_map: &CodeMap,
+) -> BTreeMap<usize, Opcode> {
+    (from..to)
+        .filter(|p| code_map.is_instruction(*p))
+        .filter_map(|p| Some((p, decode(data, p, to)?)))
+        .collect()
+}
+
+/// Instructions for analyses that search the whole image for patterns: everything `code_map`
+/// reached, plus whatever decodes linearly in between, as the game enters most of its routines
+/// through pointers that [`discover`] cannot follow. A swept instruction is dropped if it would
+/// overlap a discovered one. Translations use [`plan_instructions`] instead, since a swept
+/// instruction may well be data.
+pub fn sweep_instructions(data: &[u16], code_map: &CodeMap) -> BTreeMap<usize, Opcode> {
+    let mut instructions = BTreeMap::new();
+    let mut pointer = 0;
+    while pointer < data.len() {
+        let Some(opcode) = decode(data, pointer, data.len()) else {
+            pointer += 1;
+            continue;
+        };
+        let end = pointer + 1 + opcode.args();
+        let discovered = code_map.is_instruction(pointer);
+        if !discovered && (pointer..end).any(|p| code_map.kinds[p] != WordKind::Data) {
+            pointer += 1;
+            continue;
+        }
+        instructions.insert(pointer, opcode);
+        pointer = end;
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    /// `call r1` reaches `tail` only through the `set` before it, and the last two words decode as
+    /// `call 0` without anything jumping there.
+    const PROGRAM: &str = "
+        set r0, 5
+        call sub
+        jt r0, done
+        out 'x'
+    done:
+        halt
+    sub:
+        add r0, r0, 1
+        set r1, tail
+        call r1
+        ret
+    tail:
+        ret
+        .word 17, 0
+    ";
+
+    #[test]
+    fn follows_jumps_calls_and_registers_loaded_with_a_constant() {
+        let data = assemble(PROGRAM).unwrap();
+        let code_map = discover(&data, &[0]);
+        assert_eq!(code_map.calls, BTreeSet::from([11, 21]));
+        assert_eq!(code_map.indirect, BTreeMap::from([(18, Some(21))]));
+        assert!(code_map.is_target(10));
+        assert_eq!(code_map.kinds[8], WordKind::Instruction);
+        assert_eq!(code_map.kinds[9], WordKind::Operand);
+        assert_eq!(&code_map.kinds[22..], &[WordKind::Data; 2]);
+    }
+
+    #[test]
+    fn only_the_sweep_decodes_unreached_words() {
+        let data = assemble(PROGRAM).unwrap();
+        let code_map = discover(&data, &[0]);
+        let planned = plan_instructions(&data, 0, data.len(), &code_map);
+        let swept = sweep_instructions(&data, &code_map);
+        assert_eq!(planned.len(), 10);
+        assert!(!planned.contains_key(&22));
+        assert_eq!(swept.get(&22), Some(&Opcode::Call));
+        assert_eq!(sweep_calls(&data), BTreeSet::from([0, 11]));
+        assert!(entry_points(&data).starts_with(&[0]));
+    }
+}
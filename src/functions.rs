@@ -0,0 +1,201 @@
+use crate::Opcode;
+use crate::cfg::{Cfg, EdgeKind};
+use crate::discovery::CodeMap;
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+pub struct Function {
+    pub entry: usize,
+    /// Start addresses of the basic blocks reachable from `entry` up to its `ret`s.
+    pub blocks: BTreeSet<usize>,
+    /// Lowest block start and highest block end of the function.
+    pub extent: (usize, usize),
+    pub callers: BTreeSet<usize>,
+    pub callees: BTreeSet<usize>,
+    /// Addresses of `call rX` instructions inside the function, with the target if it was resolved.
+    pub indirect_calls: BTreeMap<usize, Option<usize>>,
+}
+
+pub struct CallGraph {
+    pub functions: BTreeMap<usize, Function>,
+}
+
+impl CallGraph {
+    /// Treats address 0 and every direct `call` target as a function entry.
+    pub fn build(data: &[u16], code_map: &CodeMap, cfg: &Cfg) -> CallGraph {
+        let mut functions = BTreeMap::new();
+        for entry in std::iter::once(0).chain(code_map.calls.iter().copied()) {
+            let blocks = cfg.function_blocks(entry);
+            if blocks.is_empty() {
+                continue;
+            }
+            let mut callees = BTreeSet::new();
+            let mut indirect_calls = BTreeMap::new();
+            let mut extent = (usize::MAX, 0);
+            for start in &blocks {
+                let block = &cfg.blocks[start];
+                extent = (extent.0.min(block.start), extent.1.max(block.end));
+                if data[block.last] != Opcode::Call.code() {
+                    continue;
+                }
+                match code_map.indirect.get(&block.last) {
+                    Some(target) => {
+                        indirect_calls.insert(block.last, *target);
+                    }
+                    None => callees.extend(
+                        block
+                            .edges
+                            .iter()
+                            .filter(|e| e.kind == EdgeKind::Call)
+                            .map(|e| e.to),
+                    ),
+                }
+            }
+            functions.insert(
+                entry,
+                Function {
+                    entry,
+                    blocks,
+                    extent,
+                    callers: BTreeSet::new(),
+                    callees,
+                    indirect_calls,
+                },
+            );
+        }
+
+        let edges: Vec<(usize, usize)> = functions
+            .values()
+            .flat_map(|f| {
+                let resolved = f.indirect_calls.values().flatten();
+                f.callees
+                    .iter()
+                    .chain(resolved)
+                    .map(|callee| (f.entry, *callee))
+            })
+            .collect();
+        for (caller, callee) in edges {
+            if let Some(function) = functions.get_mut(&callee) {
+                function.callers.insert(caller);
+            }
+        }
+        CallGraph { functions }
+    }
+
+    pub fn to_text(&self, symbols: &Symbols) -> Result<String, std::fmt::Error> {
+        let mut writer = String::new();
+        for f in self.functions.values() {
+            writeln!(
+                writer,
+                "{}: {}..{} ({} blocks)",
+                symbols.label(f.entry),
+                f.extent.0,
+                f.extent.1,
+                f.blocks.len()
+            )?;
+            writeln!(writer, "  callers: {}", join(&f.callers, symbols))?;
+            writeln!(writer, "  callees: {}", join(&f.callees, symbols))?;
+            for (site, target) in &f.indirect_calls {
+                match target {
+                    Some(target) => writeln!(
+                        writer,
+                        "  indirect call at {site} -> {}",
+                        symbols.label(*target)
+                    )?,
+                    None => writeln!(writer, "  indirect call at {site} -> ?")?,
+                }
+            }
+        }
+        Ok(writer)
+    }
+
+    /// Renders the call graph as a Graphviz digraph. Indirect calls are dashed, unresolved ones
+    /// point to a shared `?` node.
+    pub fn to_dot(&self, symbols: &Symbols, name: &str) -> Result<String, std::fmt::Error> {
+        let mut writer = String::new();
+        writeln!(writer, "digraph \"{name}\" {{")?;
+        writeln!(writer, "  node [shape=box, fontname=monospace];")?;
+        for f in self.functions.values() {
+            writeln!(
+                writer,
+                "  f{} [label=\"{}\\n{}..{}\"];",
+                f.entry,
+                symbols.label(f.entry),
+                f.extent.0,
+                f.extent.1
+            )?;
+        }
+        if self
+            .functions
+            .values()
+            .any(|f| f.indirect_calls.values().any(Option::is_none))
+        {
+            writeln!(writer, "  indirect [label=\"?\", shape=circle];")?;
+        }
+        for f in self.functions.values() {
+            for callee in &f.callees {
+                writeln!(writer, "  f{} -> f{callee};", f.entry)?;
+            }
+            for target in f.indirect_calls.values() {
+                match target {
+                    Some(target) => {
+                        writeln!(writer, "  f{} -> f{target} [style=dashed];", f.entry)?
+                    }
+                    None => writeln!(writer, "  f{} -> indirect [style=dashed];", f.entry)?,
+                }
+            }
+        }
+        writeln!(writer, "}}")?;
+        Ok(writer)
+    }
+}
+
+fn join(addresses: &BTreeSet<usize>, symbols: &Symbols) -> String {
+    addresses
+        .iter()
+        .map(|a| symbols.label(*a))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::discovery::discover;
+
+    #[test]
+    fn direct_and_resolved_indirect_calls_link_callers_and_callees() {
+        let data = assemble(
+            "
+            call sub
+            halt
+        sub:
+            set r1, tail
+            call r1
+            call r2
+            ret
+        tail:
+            ret
+            ",
+        )
+        .unwrap();
+        let code_map = discover(&data, &[0]);
+        let cfg = Cfg::build(&data, &code_map);
+        let call_graph = CallGraph::build(&data, &code_map, &cfg);
+        let functions = &call_graph.functions;
+        assert_eq!(
+            functions.keys().copied().collect::<Vec<_>>(),
+            vec![0, 3, 11]
+        );
+        assert_eq!(functions[&0].callees, BTreeSet::from([3]));
+        assert_eq!(functions[&3].callers, BTreeSet::from([0]));
+        assert_eq!(functions[&3].extent, (3, 11));
+        assert_eq!(
+            functions[&3].indirect_calls,
+            BTreeMap::from([(6, Some(11)), (8, None)])
+        );
+        assert_eq!(functions[&11].callers, BTreeSet::from([3]));
+    }
+}
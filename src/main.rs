@@ -1,12 +1,27 @@
-use crate::renderer_c::render_c_to_string;
-use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
 use std::ops::Not;
-use std::time::Instant;
 
+mod asm;
+mod cfg;
+mod coins;
+mod decoder;
+mod disasm;
+mod discovery;
+mod functions;
+mod patch;
 mod renderer_c;
-
-#[derive(Debug)]
+mod renderer_rust;
+mod snapshot;
+mod strings;
+mod structure;
+mod symbols;
+mod teleporter;
+mod vault;
+mod vm;
+mod xref;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum Opcode {
     Halt,
     Set,
@@ -32,35 +47,77 @@ enum Opcode {
     Noop,
 }
 impl Opcode {
+    const ALL: [Opcode; 22] = [
+        Opcode::Halt,
+        Opcode::Set,
+        Opcode::Push,
+        Opcode::Pop,
+        Opcode::Eq,
+        Opcode::Gt,
+        Opcode::Jmp,
+        Opcode::Jt,
+        Opcode::Jf,
+        Opcode::Add,
+        Opcode::Mult,
+        Opcode::Mod,
+        Opcode::And,
+        Opcode::Or,
+        Opcode::Not,
+        Opcode::Rmem,
+        Opcode::Wmem,
+        Opcode::Call,
+        Opcode::Ret,
+        Opcode::Out,
+        Opcode::In,
+        Opcode::Noop,
+    ];
+
     fn of(code: u16) -> Self {
-        match code {
-            0 => Opcode::Halt,
-            1 => Opcode::Set,
-            2 => Opcode::Push,
-            3 => Opcode::Pop,
-            4 => Opcode::Eq,
-            5 => Opcode::Gt,
-            6 => Opcode::Jmp,
-            7 => Opcode::Jt,
-            8 => Opcode::Jf,
-            9 => Opcode::Add,
-            10 => Opcode::Mult,
-            11 => Opcode::Mod,
-            12 => Opcode::And,
-            13 => Opcode::Or,
-            14 => Opcode::Not,
-            15 => Opcode::Rmem,
-            16 => Opcode::Wmem,
-            17 => Opcode::Call,
-            18 => Opcode::Ret,
-            19 => Opcode::Out,
-            20 => Opcode::In,
-            21 => Opcode::Noop,
-            _ => {
+        match Self::try_of(code) {
+            Some(opcode) => opcode,
+            None => {
                 panic!("Unknown opcode: {}", code)
             }
         }
     }
+    fn try_of(code: u16) -> Option<Self> {
+        Self::ALL.get(code as usize).copied()
+    }
+    fn code(&self) -> u16 {
+        *self as u16
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            Opcode::Halt => "halt",
+            Opcode::Set => "set",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Eq => "eq",
+            Opcode::Gt => "gt",
+            Opcode::Jmp => "jmp",
+            Opcode::Jt => "jt",
+            Opcode::Jf => "jf",
+            Opcode::Add => "add",
+            Opcode::Mult => "mult",
+            Opcode::Mod => "mod",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Not => "not",
+            Opcode::Rmem => "rmem",
+            Opcode::Wmem => "wmem",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Out => "out",
+            Opcode::In => "in",
+            Opcode::Noop => "noop",
+        }
+    }
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|opcode| opcode.name() == name)
+    }
     fn args(&self) -> usize {
         match self {
             Opcode::Halt => 0,
@@ -89,361 +146,279 @@ impl Opcode {
     }
 }
 
-fn _6049(mut r0: u16, mut r1: u16, mut r7: u16, cache: &mut HashMap<u64, u16>) -> u16 {
-    if let Some(r) = cache.get(&((r0 as u64) * 32768 + r1 as u64)) {
-        return *r;
-    }
-    if r0 != 0 {
-        if r1 != 0 {
-            r1 = (r1 + 32767) & 32767;
-            let r = _6049(r0, r1, r7, cache);
-            cache.insert((r0 as u64) * 32768 + r1 as u64, r);
-            r1 = r;
-
-            r0 = (r0 + 32767) & 32767;
-            let r = _6049(r0, r1, r7, cache);
-            cache.insert((r0 as u64) * 32768 + r1 as u64, r);
-            return r;
-        } else {
-            r0 = (r0 + 32767) & 32767;
-            r1 = r7;
-            let r = _6049(r0, r1, r7, cache);
-            cache.insert((r0 as u64) * 32768 + r1 as u64, r);
-            return r;
+fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let symbols_path = take_option(&mut args, "--symbols")?;
+    let mut symbols = match &symbols_path {
+        Some(path) => symbols::Symbols::load(path)?,
+        None => symbols::Symbols::default(),
+    };
+    let boot = match take_option(&mut args, "--boot")? {
+        Some(until) => Some(snapshot::Until::parse(&until).ok_or(format!(
+            "--boot: expected `in` or an address, got `{until}`"
+        ))?),
+        None => None,
+    };
+    let patch = match take_option(&mut args, "--patch")? {
+        Some(path) => patch::Patch::load(&path)?,
+        None => patch::Patch::default(),
+    };
+    let input = match take_option(&mut args, "--input")? {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    let json = take_flag(&mut args, "--json");
+    let all = take_flag(&mut args, "--all");
+    let trim = take_flag(&mut args, "--trim");
+    let threads = match take_option(&mut args, "--threads")? {
+        Some(threads) => threads.parse()?,
+        None => std::thread::available_parallelism().map_or(1, usize::from),
+    };
+    let load = |path: &str| load_booted(path, &patch, boot, &input);
+    let image_path = args.get(2).map(String::as_str).unwrap_or("challenge.bin");
+    match args.get(1).map(String::as_str) {
+        Some("disasm") => {
+            let image = load(image_path)?;
+            std::io::stdout().write_all(disasm::disassemble(&image, &symbols)?.as_bytes())?;
+            return Ok(());
         }
-    } else {
-        let r = (r1 + 1) & 32767;
-        cache.insert((r0 as u64) * 32768 + r1 as u64, r);
-        return r;
-    }
-}
-
-enum Direction {
-    North,
-    East,
-    South,
-    West,
-}
-
-impl Direction {
-    fn as_str(&self) -> &'static str {
-        match self {
-            Direction::North => "north",
-            Direction::East => "east",
-            Direction::South => "south",
-            Direction::West => "west",
+        Some("asm") => {
+            let input = args.get(2).map(String::as_str).unwrap_or("-");
+            let source = match input {
+                "-" => std::io::read_to_string(std::io::stdin())?,
+                path => fs::read_to_string(path)?,
+            };
+            let image = match asm::assemble(&source) {
+                Ok(image) => image,
+                Err(e) => {
+                    eprintln!("{input}:{e}");
+                    std::process::exit(1);
+                }
+            };
+            write_image(
+                args.get(3).map(String::as_str).unwrap_or("out.bin"),
+                &image,
+                trim,
+            )?;
+            return Ok(());
         }
-    }
-}
-
-#[derive(Debug, Copy, Clone)]
-enum Operation {
-    Add,
-    Multiply,
-    Subtract,
-}
-
-impl Operation {
-    fn execute(&self, a: i32, b: i32) -> i32 {
-        match self {
-            Operation::Add => a + b,
-            Operation::Multiply => a * b,
-            Operation::Subtract => a - b,
+        Some("cfg") => {
+            let image = load(image_path)?;
+            let code_map = discovery::discover(&image, &discovery::entry_points(&image));
+            let cfg = cfg::Cfg::build(&image, &code_map);
+            let dot = match args.get(3) {
+                Some(entry) => {
+                    let entry: usize = entry.parse()?;
+                    cfg.to_dot(
+                        &image,
+                        &code_map,
+                        &symbols,
+                        &symbols.label(entry),
+                        Some(&cfg.function_blocks(entry)),
+                    )?
+                }
+                None => cfg.to_dot(&image, &code_map, &symbols, image_path, None)?,
+            };
+            std::io::stdout().write_all(dot.as_bytes())?;
+            return Ok(());
         }
-    }
-}
-
-enum ValueOrOperation {
-    Value(i32),
-    Operation(Operation),
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
-    let data: Vec<u8> = fs::read("challenge.bin")?;
-
-    // {
-    //     let grid = vec![
-    //         vec![
-    //             ValueOrOperation::Operation(Operation::Multiply),
-    //             ValueOrOperation::Value(8),
-    //             ValueOrOperation::Operation(Operation::Subtract),
-    //             ValueOrOperation::Value(1),
-    //         ],
-    //         vec![
-    //             ValueOrOperation::Value(4),
-    //             ValueOrOperation::Operation(Operation::Multiply),
-    //             ValueOrOperation::Value(11),
-    //             ValueOrOperation::Operation(Operation::Multiply),
-    //         ],
-    //         vec![
-    //             ValueOrOperation::Operation(Operation::Add),
-    //             ValueOrOperation::Value(4),
-    //             ValueOrOperation::Operation(Operation::Subtract),
-    //             ValueOrOperation::Value(18),
-    //         ],
-    //         vec![
-    //             ValueOrOperation::Value(0),
-    //             ValueOrOperation::Operation(Operation::Subtract),
-    //             ValueOrOperation::Value(9),
-    //             ValueOrOperation::Operation(Operation::Multiply),
-    //         ],
-    //     ];
-    //     let value = 22;
-    //     let target = 30;
-    //     let limit = 12;
-    //
-    //     fn find_path(
-    //         current_path: Vec<&'static str>,
-    //         position: (usize, usize),
-    //         value: i32,
-    //         grid: &Vec<Vec<ValueOrOperation>>,
-    //         target_position: (usize, usize),
-    //         target_value: i32,
-    //         steps_limit: u32,
-    //         pending_operation: Option<Operation>,
-    //     ) -> Option<Vec<&'static str>> {
-    //         if position == target_position && value == target_value {
-    //             return Some(current_path);
-    //         }
-    //         if current_path.len() >= steps_limit as usize {
-    //             return None;
-    //         }
-    //         if position == target_position && value != target_value {
-    //             return None;
-    //         }
-    //
-    //         let mut result: Option<Vec<&'static str>> = None;
-    //
-    //         if position.0 > 0 {
-    //             let next_position = (position.0 - 1, position.1);
-    //             let mut vec = current_path.clone();
-    //             vec.push("north");
-    //             if let Some(path) = match &grid[next_position.0][next_position.1] {
-    //                 ValueOrOperation::Value(v) => {
-    //                     let value = pending_operation.unwrap().execute(value, *v);
-    //                     if value > 0 {
-    //                         find_path(
-    //                             vec,
-    //                             next_position,
-    //                             value,
-    //                             grid,
-    //                             target_position,
-    //                             target_value,
-    //                             steps_limit,
-    //                             None,
-    //                         )
-    //                     } else {
-    //                         None
-    //                     }
-    //                 }
-    //                 ValueOrOperation::Operation(op) => find_path(
-    //                     vec,
-    //                     next_position,
-    //                     value,
-    //                     grid,
-    //                     target_position,
-    //                     target_value,
-    //                     steps_limit,
-    //                     Some(*op),
-    //                 ),
-    //             } {
-    //                 match &result {
-    //                     None => {
-    //                         result = Some(path);
-    //                     }
-    //                     Some(p) => {
-    //                         if p.len() > path.len() {
-    //                             result = Some(path);
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //
-    //         if position.0 < 3 {
-    //             let next_position = (position.0 + 1, position.1);
-    //             if next_position != (3, 0) {
-    //                 let mut vec = current_path.clone();
-    //                 vec.push("south");
-    //                 if let Some(path) = match &grid[next_position.0][next_position.1] {
-    //                     ValueOrOperation::Value(v) => {
-    //                         let value = pending_operation.unwrap().execute(value, *v);
-    //                         if value > 0 {
-    //                             find_path(
-    //                                 vec,
-    //                                 next_position,
-    //                                 value,
-    //                                 grid,
-    //                                 target_position,
-    //                                 target_value,
-    //                                 steps_limit,
-    //                                 None,
-    //                             )
-    //                         } else {
-    //                             None
-    //                         }
-    //                     }
-    //                     ValueOrOperation::Operation(op) => find_path(
-    //                         vec,
-    //                         next_position,
-    //                         value,
-    //                         grid,
-    //                         target_position,
-    //                         target_value,
-    //                         steps_limit,
-    //                         Some(*op),
-    //                     ),
-    //                 } {
-    //                     match &result {
-    //                         None => {
-    //                             result = Some(path);
-    //                         }
-    //                         Some(p) => {
-    //                             if p.len() > path.len() {
-    //                                 result = Some(path);
-    //                             }
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //
-    //         if position.1 > 0 {
-    //             let next_position = (position.0, position.1 - 1);
-    //             if next_position != (3, 0) {
-    //                 let mut vec = current_path.clone();
-    //                 vec.push("west");
-    //                 if let Some(path) = match &grid[next_position.0][next_position.1] {
-    //                     ValueOrOperation::Value(v) => {
-    //                         let value = pending_operation.unwrap().execute(value, *v);
-    //                         if value > 0 {
-    //                             find_path(
-    //                                 vec,
-    //                                 next_position,
-    //                                 value,
-    //                                 grid,
-    //                                 target_position,
-    //                                 target_value,
-    //                                 steps_limit,
-    //                                 None,
-    //                             )
-    //                         } else {
-    //                             None
-    //                         }
-    //                     }
-    //                     ValueOrOperation::Operation(op) => find_path(
-    //                         vec,
-    //                         next_position,
-    //                         value,
-    //                         grid,
-    //                         target_position,
-    //                         target_value,
-    //                         steps_limit,
-    //                         Some(*op),
-    //                     ),
-    //                 } {
-    //                     match &result {
-    //                         None => {
-    //                             result = Some(path);
-    //                         }
-    //                         Some(p) => {
-    //                             if p.len() > path.len() {
-    //                                 result = Some(path);
-    //                             }
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //
-    //         if position.1 < 3 {
-    //             let next_position = (position.0, position.1 + 1);
-    //             let mut vec = current_path.clone();
-    //             vec.push("east");
-    //             if let Some(path) = match &grid[next_position.0][next_position.1] {
-    //                 ValueOrOperation::Value(v) => {
-    //                     let value = pending_operation.unwrap().execute(value, *v);
-    //                     if value > 0 {
-    //                         find_path(
-    //                             vec,
-    //                             next_position,
-    //                             value,
-    //                             grid,
-    //                             target_position,
-    //                             target_value,
-    //                             steps_limit,
-    //                             None,
-    //                         )
-    //                     } else {
-    //                         None
-    //                     }
-    //                 }
-    //                 ValueOrOperation::Operation(op) => find_path(
-    //                     vec,
-    //                     next_position,
-    //                     value,
-    //                     grid,
-    //                     target_position,
-    //                     target_value,
-    //                     steps_limit,
-    //                     Some(*op),
-    //                 ),
-    //             } {
-    //                 match &result {
-    //                     None => {
-    //                         result = Some(path);
-    //                     }
-    //                     Some(p) => {
-    //                         if p.len() > path.len() {
-    //                             result = Some(path);
-    //                         }
-    //                     }
-    //                 }
-    //             }
-    //         }
-    //
-    //         result
-    //     }
-    //
-    //     let path = find_path(vec![], (3, 0), value, &grid, (0, 3), target, limit, None);
-    //     println!("path: {:?}", path);
-    //     return Ok(())
-    // }
-
-    // {
-    //     let mut cache: HashMap<u64, u16> = HashMap::new();
-    //     let now = Instant::now();
-    //     for i in 1..32768 {
-    //         let r = _6049(4, 1, i, &mut cache);
-    //         println!("{:?} {} {}s elapsed", i, r, now.elapsed().as_secs());
-    //         if r == 6 {
-    //             break; //25734
-    //         }
-    //         cache.clear();
-    //     }
-    //     return Ok(());
-    // }
-
-    // let mut numbers = vec![2, 3, 5, 7, 9];
-    // loop {
-    //     use rand::seq::SliceRandom;
-    //     numbers.shuffle(&mut rand::rng());
-    //
-    //     if numbers[0] + numbers[1] * (numbers[2] * numbers[2]) + (numbers[3] * numbers[3] * numbers[3]) - numbers[4] == 399 {
-    //         println!("{:?}", numbers);
-    //         return Ok(());
-    //     }
-    // }
-
-    let mut code_u16 = Vec::<u16>::with_capacity(data.len() / 2);
-    for i in 0..data.len() / 2 {
-        code_u16.push(read_u16(&data[i * 2..]));
+        Some("functions") | Some("callgraph") => {
+            let image = load(image_path)?;
+            let code_map = discovery::discover(&image, &discovery::entry_points(&image));
+            let cfg = cfg::Cfg::build(&image, &code_map);
+            let call_graph = functions::CallGraph::build(&image, &code_map, &cfg);
+            let output = if args[1] == "callgraph" {
+                call_graph.to_dot(&symbols, image_path)?
+            } else {
+                call_graph.to_text(&symbols)?
+            };
+            std::io::stdout().write_all(output.as_bytes())?;
+            return Ok(());
+        }
+        Some("xref") => {
+            let image = load(image_path)?;
+            let only = match args.get(3) {
+                Some(address) => Some(address.parse()?),
+                None => None,
+            };
+            let xrefs = xref::Xrefs::build(&image);
+            std::io::stdout().write_all(xrefs.to_text(&image, &symbols, only)?.as_bytes())?;
+            return Ok(());
+        }
+        Some("teleporter") => {
+            let r0 = args.get(2).map_or(Ok(4), |r0| r0.parse())?;
+            let result = args.get(3).map_or(Ok(6), |result| result.parse())?;
+            let progress = |checked| eprint!("\rchecked {checked}/32767");
+            let found = teleporter::search_teleporter(r0, result, threads, all, progress);
+            eprintln!();
+            for r7 in found {
+                println!("{r7}");
+            }
+            return Ok(());
+        }
+        Some("verify-teleporter") => {
+            let image = load(image_path)?;
+            let routine = args.get(3).map_or(Ok(6049), |routine| routine.parse())?;
+            let verification = teleporter::verify(&image, routine)?;
+            for m in &verification.mismatches {
+                println!(
+                    "f({}, {}) with r7 = {}: solver {}, routine {}",
+                    m.r0, m.r1, m.r7, m.expected, m.actual
+                );
+            }
+            println!(
+                "{} cases checked, {} skipped, {} mismatches",
+                verification.checked,
+                verification.skipped,
+                verification.mismatches.len()
+            );
+            if !verification.mismatches.is_empty() {
+                return Err(format!("the solver disagrees with the routine at {routine}").into());
+            }
+            return Ok(());
+        }
+        Some("confirmation") => {
+            let image = load(image_path)?;
+            let confirmations = teleporter::find_confirmations(&image);
+            if confirmations.is_empty() {
+                return Err("no call after an r7 test is compared and branched on".into());
+            }
+            let mut patch = patch::Patch::default();
+            for confirmation in confirmations {
+                println!(
+                    "# {}: call {} expecting {} in r{}, r7 tested at {}",
+                    symbols.label(confirmation.call),
+                    symbols.label(confirmation.routine),
+                    confirmation.expected,
+                    confirmation.flag - 32768,
+                    symbols.label(confirmation.r7_test)
+                );
+                let (address, words) = confirmation.patch();
+                patch.edits.push(patch::Edit {
+                    address,
+                    words: words.to_vec(),
+                    expected: Some(image[address..address + words.len()].to_vec()),
+                    line: 0,
+                });
+            }
+            std::io::stdout().write_all(patch.to_text()?.as_bytes())?;
+            return Ok(());
+        }
+        Some("write") => {
+            let image = load(image_path)?;
+            write_image(
+                args.get(3).map(String::as_str).unwrap_or("out.bin"),
+                &image,
+                trim,
+            )?;
+            return Ok(());
+        }
+        Some("diff") => {
+            let before = load_image(image_path)?;
+            let Some(after_path) = args.get(3) else {
+                return Err("diff: missing the second image".into());
+            };
+            let after = load_image(after_path)?;
+            std::io::stdout()
+                .write_all(patch::Patch::diff(&before, &after).to_text()?.as_bytes())?;
+            return Ok(());
+        }
+        Some("coins") => {
+            let text = args.get(2).map(String::as_str).unwrap_or(coins::MONUMENT);
+            let equation = coins::Equation::parse(text).map_err(|e| format!("equation at {e}"))?;
+            let solutions = coins::solve(&equation, &coins::COINS);
+            if solutions.is_empty() {
+                return Err(format!("no order of the coins solves `{text}`").into());
+            }
+            for (i, solution) in solutions.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                for command in coins::commands(solution) {
+                    println!("{command}");
+                }
+            }
+            return Ok(());
+        }
+        Some("monument") => {
+            let image = load_patched(image_path, &patch)?;
+            let mut vm = snapshot::boot(&image, snapshot::Until::Input, &input)?;
+            let (commands, answer) = coins::solve_monument(&mut vm)?;
+            for command in commands {
+                println!("{command}");
+            }
+            eprint!("{answer}");
+            return Ok(());
+        }
+        Some("vault") => {
+            let image = load_booted(
+                image_path,
+                &patch,
+                Some(boot.unwrap_or(snapshot::Until::Input)),
+                &input,
+            )?;
+            let path = vault::Vault::from_memory(&image)?
+                .solve()
+                .ok_or("no walk through the vault within the step limit")?;
+            for direction in path {
+                println!("{}", direction.as_str());
+            }
+            return Ok(());
+        }
+        Some("boot") => {
+            let image = load_patched(image_path, &patch)?;
+            let vm = snapshot::boot(&image, boot.unwrap_or(snapshot::Until::Input), &input)?;
+            let memory = snapshot::memory_image(&vm, image.len());
+            write_image(
+                args.get(3).map(String::as_str).unwrap_or("snapshot.bin"),
+                &memory,
+                trim,
+            )?;
+            std::io::stdout().write_all(snapshot::to_text(&image, &vm).as_bytes())?;
+            return Ok(());
+        }
+        Some("strings") => {
+            let image = load(image_path)?;
+            let strings = strings::find_strings(&image);
+            let output = if json {
+                strings::to_json(&strings)?
+            } else {
+                strings::to_text(&strings)?
+            };
+            std::io::stdout().write_all(output.as_bytes())?;
+            return Ok(());
+        }
+        Some("decode") => {
+            let image = load_booted(
+                image_path,
+                &patch,
+                Some(boot.unwrap_or(snapshot::Until::Input)),
+                &input,
+            )?;
+            let (routine, decoded) = decoder::decode_messages(&image)?;
+            std::io::stdout()
+                .write_all(decoder::to_text(routine, &decoded, &symbols)?.as_bytes())?;
+            return Ok(());
+        }
+        Some("dump-c") => {
+            let image = load(image_path)?;
+            renderer_c::render(&image, "dump.c", &symbols)?;
+            return Ok(());
+        }
+        Some("dump-rust") => {
+            let image = load(image_path)?;
+            renderer_rust::render(&image, "dump.rs")?;
+            return Ok(());
+        }
+        Some("decompile") => {
+            let image = load(image_path)?;
+            renderer_c::render_structured(&image, "decompiled.c", &symbols)?;
+            return Ok(());
+        }
+        _ => {}
     }
 
-    // renderer_c::render(&code_u16, "dump.c")?;
-    // if true {
-    //     return Ok(());
-    // }
+    let mut code_u16 = load_patched("challenge.bin", &patch)?;
 
     let mem = code_u16.as_mut_slice();
     let mut p = 0;
@@ -453,54 +428,14 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     let mut debug_r7 = false;
     let mut input: Vec<char> = Vec::new();
 
-    let mut input_commands = vec![
-        "doorway",
-        "north",
-        "north",
-        "bridge",
-        "continue",
-        "down",
-        "east",
-        "take empty lantern",
-        "west",
-        "west",
-        "west",
-        "passage",
-        "ladder",
-        "west",
-        "south",
-        "north",
-        "take can",
-        "west",
-        "use can",
-        "ladder",
-        "use lantern",
-        "darkness",
-        "continue",
-        "west",
-        "west",
-        "west",
-        "west",
-        "north",
-        "take red coin",
-        "north",
-        "east",
-        "take concave coin",
-        "down",
-        "take corroded coin",
-        "up",
-        "west",
-        "west",
-        "take blue coin",
-        "up",
-        "take shiny coin",
-        "down",
-        "east",
-        "use blue coin",
-        "use red coin",
-        "use shiny coin",
-        "use concave coin",
-        "use corroded coin",
+    let mut input_commands: Vec<String> = include_str!("../to_monument.txt")
+        .lines()
+        .rev()
+        .map(str::to_string)
+        .collect();
+    let mut monument_solved = false;
+
+    let commands_after_monument = [
         "north",
         "take teleporter",
         "use teleporter",
@@ -509,9 +444,7 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
         "look strange book",
     ];
 
-    input_commands.reverse();
-
-    let mut commands_after_teleport = vec![
+    let commands_after_teleport = [
         "north",
         "north",
         "north",
@@ -525,15 +458,8 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
         "north",
         "north",
         "take orb",
-
-        "north", "east", "east", "north", "west", "south", "east", "east", "west", "north", "north", "east",
-
-        "vault",
-        "look mirror",
-        "take mirror",
-        "use mirror",
     ];
-    commands_after_teleport.reverse();
+    let commands_after_vault = ["vault", "look mirror", "take mirror", "use mirror"];
 
     loop {
         let code = mem[p];
@@ -578,7 +504,11 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
             Opcode::Jmp => {
                 let code = mem[p];
                 if debug {
-                    println!("Jmp to {} from {}", code, p);
+                    println!(
+                        "Jmp to {} from {}",
+                        symbols.label(code as usize),
+                        symbols.label(p)
+                    );
                 }
                 p = code as usize;
             }
@@ -588,7 +518,11 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 let b = mem[p];
                 p += 1;
                 if debug {
-                    println!("Jt {a} to {} from {}", b, p);
+                    println!(
+                        "Jt {a} to {} from {}",
+                        symbols.label(b as usize),
+                        symbols.label(p)
+                    );
                 }
                 if a != 0 {
                     p = b as usize;
@@ -601,7 +535,11 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 let b = mem[p];
                 p += 1;
                 if debug {
-                    println!("Jf {a} to {} from {}", b, p);
+                    println!(
+                        "Jf {a} to {} from {}",
+                        symbols.label(b as usize),
+                        symbols.label(p)
+                    );
                 }
                 if a == 0 {
                     p = b as usize;
@@ -672,7 +610,7 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 let b = to_value(mem[p], &registers);
                 p += 1;
                 if debug {
-                    println!("Wmem {b} to {}", a);
+                    println!("Wmem {b} to {}", symbols.label(a as usize));
                 }
                 mem[a as usize] = b;
             }
@@ -746,7 +684,11 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 let value = to_value(mem[p], &registers);
                 p += 1;
                 if debug {
-                    println!("Call {value} from {p}");
+                    println!(
+                        "Call {} from {}",
+                        symbols.label(value as usize),
+                        symbols.label(p)
+                    );
                 }
                 stack.push(p as u16);
                 p = value as usize;
@@ -758,7 +700,7 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 }
                 let address = stack.pop().unwrap();
                 if debug {
-                    println!("Ret {address}");
+                    println!("Ret {}", symbols.label(address as usize));
                 }
                 p = address as usize;
             }
@@ -777,10 +719,24 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                 if input.is_empty() {
                     let mut input_string = String::new();
                     if debug {
-                        println!("In at {a}; {p}");
+                        println!("In at {a}; {}", symbols.label(p));
                     }
                     println!("waiting for input..");
 
+                    if input_commands.is_empty() && !monument_solved {
+                        // solve the monument in a copy of the game, standing in front of it
+                        monument_solved = true;
+                        let mut vm = vm::Vm::new(mem)?;
+                        vm.pc = p - 2;
+                        vm.registers = registers;
+                        vm.stack = stack.clone();
+                        let (coin_commands, _) = coins::solve_monument(&mut vm)?;
+                        input_commands = coin_commands
+                            .into_iter()
+                            .chain(commands_after_monument.map(str::to_string))
+                            .rev()
+                            .collect();
+                    }
                     if input_commands.is_empty().not() {
                         let c = input_commands.pop().unwrap();
                         println!("using input: {c}");
@@ -789,23 +745,50 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
                     } else {
                         if !debug_r7 {
                             // disable check
-                            mem[5508] = 21;
-                            mem[5509] = 21;
-                            mem[5510] = 1;
-                            mem[5511] = 32768;
-                            mem[5512] = 6;
+                            for confirmation in teleporter::find_confirmations(mem) {
+                                let (address, words) = confirmation.patch();
+                                mem[address..address + words.len()].copy_from_slice(&words);
+                            }
 
                             // renderer_c::render(&mem, "dump2.c")?;
                             debug_r7 = true;
-                            registers[7] = 25734;
+                            registers[7] = teleporter::solve_teleporter(4, 6)
+                                .first()
+                                .copied()
+                                .ok_or("no r7 passes the teleporter check")?;
 
                             input = "use teleporter\n".chars().collect();
-                            input_commands = commands_after_teleport.clone();
+                            let walk = vault::Vault::from_memory(mem)?
+                                .solve()
+                                .ok_or("no walk through the vault within the step limit")?;
+                            input_commands = commands_after_teleport
+                                .into_iter()
+                                .chain(walk.iter().map(vault::Direction::as_str))
+                                .chain(commands_after_vault)
+                                .rev()
+                                .map(str::to_string)
+                                .collect();
                         } else {
-                            let _input_length = std::io::stdin()
-                                .read_line(&mut input_string)
-                                .expect("Failed to read input");
-                            // println!("Input: {input_length} - {input} to {a}");
+                            // `symbol=addr name [comment]` names an address while playing
+                            loop {
+                                input_string.clear();
+                                let _input_length = std::io::stdin()
+                                    .read_line(&mut input_string)
+                                    .expect("Failed to read input");
+                                // println!("Input: {input_length} - {input} to {a}");
+                                let Some(line) = input_string.strip_prefix("symbol=") else {
+                                    break;
+                                };
+                                match symbols.add_line(line) {
+                                    Ok(()) => {
+                                        if let Some(path) = &symbols_path {
+                                            symbols.save(path)?;
+                                        }
+                                        println!("symbol added");
+                                    }
+                                    Err(e) => println!("symbol: {e}"),
+                                }
+                            }
                             if input_string.starts_with("debug=true") {
                                 debug = true;
                             }
@@ -832,6 +815,75 @@ fn to_index(a: u16) -> usize {
     (a - 32768) as usize
 }
 
+/// Removes `name value` from `args` and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|a| a == name) {
+        Some(i) if i + 1 < args.len() => Ok(args.drain(i..=i + 1).nth(1)),
+        Some(_) => Err(format!("{name} needs a value")),
+        None => Ok(None),
+    }
+}
+
+/// Removes `name` from `args` and returns whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let before = args.len();
+    args.retain(|a| a != name);
+    args.len() != before
+}
+
+/// Loads an image for analysis: as stored, or as live memory once it ran to `boot`.
+fn load_booted(
+    path: &str,
+    patch: &patch::Patch,
+    boot: Option<snapshot::Until>,
+    input: &str,
+) -> Result<Vec<u16>, Box<dyn std::error::Error>> {
+    let image = load_patched(path, patch)?;
+    match boot {
+        Some(until) => Ok(snapshot::memory_image(
+            &snapshot::boot(&image, until, input)?,
+            image.len(),
+        )),
+        None => Ok(image),
+    }
+}
+
+/// Loads an image and applies `patch` to it, naming the file when the patch does not fit.
+fn load_patched(path: &str, patch: &patch::Patch) -> Result<Vec<u16>, Box<dyn std::error::Error>> {
+    let mut image = load_image(path)?;
+    patch.apply(&mut image).map_err(|e| match &patch.path {
+        Some(patch_path) => format!("{patch_path}:{e} in {path}"),
+        None => format!("{path}: patch line {e}"),
+    })?;
+    Ok(image)
+}
+
+fn load_image(path: &str) -> std::io::Result<Vec<u16>> {
+    let data: Vec<u8> = fs::read(path)?;
+    let mut code_u16 = Vec::<u16>::with_capacity(data.len() / 2);
+    for i in 0..data.len() / 2 {
+        code_u16.push(read_u16(&data[i * 2..]));
+    }
+    Ok(code_u16)
+}
+
 fn read_u16(data: &[u8]) -> u16 {
     data[0] as u16 | ((data[1] as u16) << 8)
 }
+
+/// Writes an image in the little-endian format `load_image` reads, leaving out the trailing zeros
+/// if `trim` is set.
+fn write_image(path: &str, image: &[u16], trim: bool) -> std::io::Result<()> {
+    let used = match trim {
+        true => image
+            .iter()
+            .rposition(|w| *w != 0)
+            .map_or(0, |last| last + 1),
+        false => image.len(),
+    };
+    let mut bytes = Vec::with_capacity(used * 2);
+    for word in &image[..used] {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    fs::write(path, bytes)
+}
@@ -0,0 +1,240 @@
+use crate::snapshot::changed_ranges;
+use std::fmt;
+use std::fmt::Write;
+
+/// Words written over an image at one address.
+pub struct Edit {
+    pub address: usize,
+    pub words: Vec<u16>,
+    /// What the image has to hold there before the edit, when known.
+    pub expected: Option<Vec<u16>>,
+    /// Line of the patch file the edit comes from, for error messages.
+    pub line: usize,
+}
+
+/// Edits to a memory image, read from a patch file with one `addr words... [was words...]` entry
+/// per line.
+///
+/// Addresses are decimal or `0x` hex, words are decimal. The words after `was` are the original
+/// contents: applying the patch to an image that holds anything else fails rather than corrupting
+/// it. Blank lines and lines starting with `#` are ignored.
+#[derive(Default)]
+pub struct Patch {
+    pub edits: Vec<Edit>,
+    /// File the patch was loaded from, for error messages.
+    pub path: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct PatchError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl Patch {
+    pub fn parse(source: &str) -> Result<Patch, PatchError> {
+        let mut patch = Patch::default();
+        for (i, line) in source.lines().enumerate() {
+            let error = |message: String| PatchError {
+                line: i + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let address = fields.next().unwrap();
+            let address = match address.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => address.parse(),
+            }
+            .map_err(|_| error(format!("invalid address `{address}`")))?;
+            let rest: Vec<&str> = fields.collect();
+            let (words, expected) = match rest.iter().position(|f| *f == "was") {
+                Some(was) => (&rest[..was], Some(&rest[was + 1..])),
+                None => (&rest[..], None),
+            };
+            let words = parse_words(words).map_err(error)?;
+            let expected = expected.map(parse_words).transpose().map_err(error)?;
+            if words.is_empty() {
+                return Err(error(format!("no words for address {address}")));
+            }
+            if let Some(expected) = &expected
+                && expected.len() != words.len()
+            {
+                return Err(error(format!(
+                    "{} words replace {} original words",
+                    words.len(),
+                    expected.len()
+                )));
+            }
+            if address + words.len() > 32768 {
+                return Err(error(format!(
+                    "{} words at {address} run past the end of memory",
+                    words.len()
+                )));
+            }
+            patch.edits.push(Edit {
+                address,
+                words,
+                expected,
+                line: i + 1,
+            });
+        }
+        Ok(patch)
+    }
+
+    pub fn load(path: &str) -> Result<Patch, Box<dyn std::error::Error>> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let mut patch = Patch::parse(&source).map_err(|e| format!("{path}:{e}"))?;
+        patch.path = Some(path.to_string());
+        Ok(patch)
+    }
+
+    /// Applies every edit, growing the image with zeros when an edit ends past it. Fails without
+    /// changing anything if an edit's original words do not match the image.
+    pub fn apply(&self, image: &mut Vec<u16>) -> Result<(), PatchError> {
+        for edit in &self.edits {
+            let Some(expected) = &edit.expected else {
+                continue;
+            };
+            let found: Vec<u16> = (edit.address..edit.address + expected.len())
+                .map(|i| image.get(i).copied().unwrap_or(0))
+                .collect();
+            if found != *expected {
+                return Err(PatchError {
+                    line: edit.line,
+                    message: format!(
+                        "expected {} at {}, found {}",
+                        join_words(expected),
+                        edit.address,
+                        join_words(&found)
+                    ),
+                });
+            }
+        }
+        for edit in &self.edits {
+            let end = edit.address + edit.words.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[edit.address..end].copy_from_slice(&edit.words);
+        }
+        Ok(())
+    }
+
+    /// The patch that turns `before` into `after`, one edit per changed range, with the original
+    /// words of `before` as expected contents.
+    pub fn diff(before: &[u16], after: &[u16]) -> Patch {
+        let word = |image: &[u16], i: usize| image.get(i).copied().unwrap_or(0);
+        let edits = changed_ranges(before, after)
+            .into_iter()
+            .map(|range| Edit {
+                address: range.start,
+                words: range.clone().map(|i| word(after, i)).collect(),
+                expected: Some(range.map(|i| word(before, i)).collect()),
+                line: 0,
+            })
+            .collect();
+        Patch { edits, path: None }
+    }
+
+    pub fn to_text(&self) -> Result<String, std::fmt::Error> {
+        let mut writer = String::new();
+        for edit in &self.edits {
+            write!(writer, "{} {}", edit.address, join_words(&edit.words))?;
+            if let Some(expected) = &edit.expected {
+                write!(writer, " was {}", join_words(expected))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(writer)
+    }
+}
+
+fn parse_words(fields: &[&str]) -> Result<Vec<u16>, String> {
+    fields
+        .iter()
+        .map(|f| f.parse().map_err(|_| format!("invalid word `{f}`")))
+        .collect()
+}
+
+fn join_words(words: &[u16]) -> String {
+    let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_addresses_words_and_original_words() {
+        let patch = Patch::parse("# skip the check\n0x10 21 21 was 7 32775\n\n40 1\n").unwrap();
+        let [skip, set] = &patch.edits[..] else {
+            panic!("{} edits", patch.edits.len());
+        };
+        assert_eq!(
+            (skip.address, &skip.words, &skip.expected, skip.line),
+            (16, &vec![21, 21], &Some(vec![7, 32775]), 2)
+        );
+        assert_eq!(
+            (set.address, &set.words, &set.expected, set.line),
+            (40, &vec![1], &None, 4)
+        );
+        assert_eq!(patch.to_text().unwrap(), "16 21 21 was 7 32775\n40 1\n");
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let error = |source: &str| Patch::parse(source).err().unwrap().to_string();
+        assert_eq!(error("\n12 x"), "2: invalid word `x`");
+        assert_eq!(error("0xg 1"), "1: invalid address `0xg`");
+        assert_eq!(error("5 was 1"), "1: no words for address 5");
+        assert_eq!(error("5 1 2 was 1"), "1: 2 words replace 1 original words");
+        assert_eq!(
+            error("32767 1 2"),
+            "1: 2 words at 32767 run past the end of memory"
+        );
+    }
+
+    #[test]
+    fn applies_edits_and_grows_the_image() {
+        let patch = Patch::parse("1 9 was 2\n5 7 8").unwrap();
+        let mut image = vec![1, 2, 3];
+        patch.apply(&mut image).unwrap();
+        assert_eq!(image, vec![1, 9, 3, 0, 0, 7, 8]);
+    }
+
+    #[test]
+    fn mismatch_is_an_error_and_leaves_the_image_unchanged() {
+        // the first edit matches, the second does not
+        let patch = Patch::parse("0 9 was 1\n2 9 9 was 3 5").unwrap();
+        let mut image = vec![1, 2, 3, 4];
+        let error = patch.apply(&mut image).unwrap_err();
+        assert_eq!(error.to_string(), "2: expected 3 5 at 2, found 3 4");
+        assert_eq!(image, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn diff_applied_to_the_first_image_gives_the_second() {
+        let before = vec![1, 2, 3, 4, 5];
+        let after = vec![1, 7, 3, 4, 5, 0, 6];
+        let patch = Patch::diff(&before, &after);
+        let mut image = before.clone();
+        Patch::parse(&patch.to_text().unwrap())
+            .unwrap()
+            .apply(&mut image)
+            .unwrap();
+        assert_eq!(image, after);
+    }
+}
@@ -1,7 +1,125 @@
 use crate::Opcode;
+use crate::cfg::Cfg;
+use crate::disasm::decode;
+use crate::discovery::{CodeMap, discover, entry_points, plan_instructions};
+use crate::functions::{CallGraph, Function};
+use crate::structure::{Condition, LoopKind, Node, structure};
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 
-pub fn render(data: &[u16], name: &str) -> std::io::Result<()> {
+pub const MAX_LITERAL_LENGTH: usize = 72;
+
+/// Runtime support for self-modifying code. Every translated label is guarded by `is_stale` over
+/// the words its straight-line code was translated from; once `write_mem` changes one of them the
+/// guard sends execution to `interpret`, which runs from `mem` until it reaches a label whose
+/// words are intact again.
+const INTERPRETER: &str = r#"void write_mem(unsigned short a, unsigned short b) {
+  if (translated[a] && stale[a] != (b != original[a])) {
+    stale[a] = !stale[a];
+    stale_count += stale[a] ? 1 : -1;
+  }
+  mem[a] = b;
+}
+
+int is_stale(unsigned short from, unsigned short to) {
+  for (unsigned short i = from; i < to; i++) {
+    if (stale[i]) return 1;
+  }
+  return 0;
+}
+
+unsigned short value_of(unsigned short a) {
+  if (a < 32768) return a;
+  if (a < 32776) return *registers[a - 32768];
+  fprintf(stderr, "Error! Invalid value %d\n", a);
+  exit(-1);
+}
+
+unsigned short *register_of(unsigned short a) {
+  if (a >= 32768 && a < 32776) return registers[a - 32768];
+  fprintf(stderr, "Error! Invalid register %d\n", a);
+  exit(-1);
+}
+
+unsigned short interpret(unsigned short pc) {
+  for (;;) {
+    if (region_end[pc] && !is_stale(pc, region_end[pc])) return pc;
+    unsigned short a = mem[(pc + 1) & 32767], b = mem[(pc + 2) & 32767], c = mem[(pc + 3) & 32767];
+    switch (mem[pc]) {
+      case 0: exit(0);
+      case 1: *register_of(a) = value_of(b); pc += 3; break;
+      case 2: push_stack(value_of(a)); pc += 2; break;
+      case 3: *register_of(a) = pop_stack(); pc += 2; break;
+      case 4: *register_of(a) = value_of(b) == value_of(c); pc += 4; break;
+      case 5: *register_of(a) = value_of(b) > value_of(c); pc += 4; break;
+      case 6: pc = value_of(a); break;
+      case 7: pc = value_of(a) != 0 ? value_of(b) : pc + 3; break;
+      case 8: pc = value_of(a) == 0 ? value_of(b) : pc + 3; break;
+      case 9: *register_of(a) = (value_of(b) + value_of(c)) % 32768; pc += 4; break;
+      case 10: *register_of(a) = ((unsigned int) value_of(b) * value_of(c)) % 32768; pc += 4; break;
+      case 11: *register_of(a) = value_of(b) % value_of(c); pc += 4; break;
+      case 12: *register_of(a) = value_of(b) & value_of(c); pc += 4; break;
+      case 13: *register_of(a) = value_of(b) | value_of(c); pc += 4; break;
+      case 14: *register_of(a) = ~value_of(b) & 32767; pc += 3; break;
+      case 15: *register_of(a) = mem[value_of(b)]; pc += 3; break;
+      case 16: write_mem(value_of(a), value_of(b)); pc += 3; break;
+      case 17: push_stack(pc + 2); pc = value_of(a); break;
+      case 18: if (stack_pointer == 0) exit(0); pc = pop_stack(); break;
+      case 19: putchar(value_of(a)); pc += 2; break;
+      case 20: *register_of(a) = read_char(); pc += 2; break;
+      case 21: pc += 1; break;
+      default: fprintf(stderr, "Error! Unknown opcode %d at %d\n", mem[pc], pc); exit(-1);
+    }
+    pc &= 32767;
+  }
+}
+"#;
+
+/// The stack, `mem` initialized with `data`, and the stack and input functions, shared by both
+/// translations.
+fn runtime(data: &[u16]) -> String {
+    let mut writer = String::new();
+    writer.push_str(
+        "int stack_pointer = 0;
+int stack_capacity = 0;
+unsigned short *stack = NULL;
+
+unsigned short mem[32768] = {
+",
+    );
+    for chunk in data.chunks(16) {
+        let line: Vec<String> = chunk.iter().map(|w| w.to_string()).collect();
+        writer.push_str(&format!("  {},\n", line.join(", ")));
+    }
+    writer.push_str(
+        "};
+
+void push_stack(unsigned short s) {
+  if (stack_pointer >= stack_capacity) {
+    stack_capacity = stack_capacity ? stack_capacity * 2 : 1024;
+    stack = realloc(stack, stack_capacity * sizeof(unsigned short));
+    if (stack == NULL) { fputs(\"Error! Stack overflow!\\n\", stderr); exit(-1); }
+  }
+  stack[stack_pointer++] = s;
+}
+
+unsigned short pop_stack() {
+  if (stack_pointer == 0) { fputs(\"Error! Pop on empty stack!\\n\", stderr); exit(-1); }
+  return stack[--stack_pointer];
+}
+
+unsigned short read_char() {
+  int c = getchar();
+  if (c == EOF) exit(0);
+  return (unsigned short) c;
+}
+",
+    );
+    writer
+}
+
+pub fn render(data: &[u16], name: &str, symbols: &Symbols) -> std::io::Result<()> {
     let file = File::create(name)?;
     use std::io::{BufWriter, Write};
     let mut writer = BufWriter::new(file);
@@ -9,123 +127,474 @@ pub fn render(data: &[u16], name: &str) -> std::io::Result<()> {
     writeln!(writer, "#include <stdlib.h>")?;
     writeln!(writer, "#include <stdio.h>")?;
     writeln!(writer, "#include <string.h>")?;
-    writeln!(writer, "")?;
-    writeln!(writer, "short r0 = 0;")?;
-    writeln!(writer, "short r1 = 0;")?;
-    writeln!(writer, "short r2 = 0;")?;
-    writeln!(writer, "short r3 = 0;")?;
-    writeln!(writer, "short r4 = 0;")?;
-    writeln!(writer, "short r5 = 0;")?;
-    writeln!(writer, "short r6 = 0;")?;
-    writeln!(writer, "short r7 = 0;")?;
-    writeln!(writer, "short label_to_go = 0;")?;
-    writeln!(writer, "int stack_pointer = 0;")?;
-    writeln!(writer, "int stack_capacity = 1024;")?;
-    writeln!(writer, "short stack[1024];")?;
-    writeln!(writer, "")?;
-
+    writeln!(writer)?;
+    writeln!(writer, "unsigned short r0 = 0;")?;
+    writeln!(writer, "unsigned short r1 = 0;")?;
+    writeln!(writer, "unsigned short r2 = 0;")?;
+    writeln!(writer, "unsigned short r3 = 0;")?;
+    writeln!(writer, "unsigned short r4 = 0;")?;
+    writeln!(writer, "unsigned short r5 = 0;")?;
+    writeln!(writer, "unsigned short r6 = 0;")?;
+    writeln!(writer, "unsigned short r7 = 0;")?;
     writeln!(
         writer,
-        "void push_stack(short s) {{
-  if (stack_pointer >= stack_capacity) {{ fputs(\"Error! Stack overflow!\\n\", stderr); exit(-1); }}
-  stack[stack_pointer++] = s;
-}}"
+        "unsigned short *const registers[8] = {{&r0, &r1, &r2, &r3, &r4, &r5, &r6, &r7}};"
     )?;
-    writeln!(writer, "")?;
+    writeln!(writer, "unsigned short label_to_go = 0;")?;
+    write!(writer, "{}", runtime(data))?;
+    writeln!(writer)?;
+    writeln!(writer, "unsigned short original[32768];")?;
+    writeln!(writer, "unsigned char translated[32768];")?;
+    writeln!(writer, "unsigned short region_end[32768];")?;
+    writeln!(writer, "unsigned char stale[32768];")?;
+    writeln!(writer, "int stale_count = 0;")?;
+    writeln!(writer)?;
+    writeln!(writer, "{INTERPRETER}")?;
 
+    let code_map = discover(data, &[0]);
+    let mut all_labels = vec![];
+    let mut string_content = String::new();
+    render_c_to_string_extended(
+        data,
+        0,
+        data.len(),
+        &mut string_content,
+        &mut all_labels,
+        &code_map,
+        symbols,
+    )
+    .unwrap();
+
+    writeln!(writer, "static const unsigned short regions[] = {{")?;
+    for chunk in all_labels.chunks(8) {
+        let line: Vec<String> = chunk
+            .iter()
+            .map(|(start, end)| format!("{start}, {end}"))
+            .collect();
+        writeln!(writer, "  {},", line.join(", "))?;
+    }
+    writeln!(writer, "}};")?;
+    writeln!(writer)?;
+
+    writeln!(writer, "int main(void) {{")?;
+    writeln!(writer, "  memcpy(original, mem, sizeof(mem));")?;
     writeln!(
         writer,
-        "short pop_stack() {{
-  if (stack_pointer == 0) exit(-1);
-  return stack[--stack_pointer];
-}}"
+        "  for (unsigned int i = 0; i < sizeof(regions) / sizeof(regions[0]); i += 2) {{
+    region_end[regions[i]] = regions[i + 1];
+    memset(translated + regions[i], 1, regions[i + 1] - regions[i]);
+  }}"
     )?;
-    writeln!(writer, "")?;
-    writeln!(writer, "int main(void) {{")?;
+    writeln!(writer, "{string_content}")?;
 
-    let mut pointer = 0;
-    let mut labels_to_print = vec![];
+    writeln!(writer, "  exit(0);")?;
+    writeln!(writer)?;
+    writeln!(writer, "  labels:")?;
+    writeln!(writer, "  switch (label_to_go) {{")?;
+    for (x, _) in &all_labels {
+        writeln!(writer, "    case {x}: goto {};", symbols.label(*x))?;
+    }
+    writeln!(writer, "    default: goto interpret;")?;
+    writeln!(writer, "  }}")?;
+    writeln!(writer)?;
+    writeln!(writer, "  interpret:")?;
+    writeln!(writer, "  label_to_go = interpret(label_to_go);")?;
+    writeln!(writer, "  goto labels;")?;
 
-    loop {
-        if pointer >= data.len() {
-            break;
-        }
+    writeln!(writer, "}}")?;
+    writer.flush()?;
+    Ok(())
+}
 
-        let code = data[pointer];
-        pointer += 1;
+/// Calling convention of the structured output, written at the top of the generated file.
+const CALLING_CONVENTION: &str = "/*
+ * Every routine of the image becomes a C function `f_N`, named after its entry address.
+ *
+ * This file is for reading and cannot run: it has no main(). The self-test routines at 1307 and
+ * 1309 pop the return address a `call` pushes on the machine, which is not on `stack` here, the
+ * self-test and the boot rewrite code that is translated once, and the game's own code is only
+ * decrypted while it boots, so it is not here unless the image was booted. dump.c is the
+ * translation that plays the game.
+ *
+ * Calling convention: the eight registers are globals shared by all functions. A `call` is a C
+ * call and `ret` a C return, so return addresses live on the C stack and `stack` only holds what
+ * `push` and `pop` put there. The game's routines take their arguments in r0, r1 and r2, return
+ * their result in r0 and push and pop every other register they change, so callers can rely on
+ * r3..r7 surviving a call. Calls through a register go through `call`, which only knows the
+ * functions listed below.
+ *
+ * `wmem` is a plain store: unlike dump.c this translation does not follow self-modifying code.
+ */";
 
-        if code > 21 {
-            continue;
+/// Translates every function found in the image into a C function with nested ifs and loops.
+///
+/// Functions are the entry point plus every literal `call` target, including those only found by
+/// sweeping the image linearly. Control flow is recovered by [`structure`]; a `goto` remains only
+/// where the flow cannot be nested.
+pub fn render_structured(data: &[u16], name: &str, symbols: &Symbols) -> std::io::Result<()> {
+    let code_map = discover(data, &entry_points(data));
+    let cfg = Cfg::build(data, &code_map);
+    let call_graph = CallGraph::build(data, &code_map, &cfg);
+
+    let mut writer = String::new();
+    render_structured_to_string(data, &cfg, &call_graph, symbols, &mut writer).unwrap();
+    std::fs::write(name, writer)
+}
+
+fn render_structured_to_string(
+    data: &[u16],
+    cfg: &Cfg,
+    call_graph: &CallGraph,
+    symbols: &Symbols,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    use std::fmt::Write;
+    writeln!(writer, "{CALLING_CONVENTION}")?;
+    writeln!(writer)?;
+    writeln!(writer, "#include <stdlib.h>")?;
+    writeln!(writer, "#include <stdio.h>")?;
+    writeln!(writer)?;
+    writeln!(writer, "unsigned short r0, r1, r2, r3, r4, r5, r6, r7;")?;
+    write!(writer, "{}", runtime(data))?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "void write_mem(unsigned short a, unsigned short b) {{
+  mem[a] = b;
+}}"
+    )?;
+    writeln!(writer)?;
+    writeln!(writer, "void call(unsigned short address);")?;
+    for entry in call_graph.functions.keys() {
+        writeln!(writer, "void {}(void);", function_name(*entry, symbols))?;
+    }
+
+    for function in call_graph.functions.values() {
+        writeln!(writer)?;
+        let callers: Vec<String> = function.callers.iter().map(|c| symbols.label(*c)).collect();
+        writeln!(
+            writer,
+            "/* {}: {}..{}, called from {} */",
+            symbols.label(function.entry),
+            function.extent.0,
+            function.extent.1,
+            if callers.is_empty() {
+                "nowhere".to_string()
+            } else {
+                callers.join(", ")
+            }
+        )?;
+        if let Some(comment) = symbols.get(function.entry).and_then(|s| s.comment.as_ref()) {
+            writeln!(writer, "/* {comment} */")?;
         }
-        let opcode = Opcode::of(code);
+        writeln!(
+            writer,
+            "void {}(void) {{",
+            function_name(function.entry, symbols)
+        )?;
+        let nodes = structure(data, cfg, &function.blocks, function.entry);
+        write_nodes(data, &call_graph.functions, symbols, &nodes, 1, writer)?;
+        writeln!(writer, "}}")?;
+    }
 
-        match opcode {
-            Opcode::Jmp => {
-                let arg = data[pointer];
-                pointer += 1;
-                labels_to_print.push(arg as usize);
+    writeln!(writer)?;
+    writeln!(writer, "void call(unsigned short address) {{")?;
+    writeln!(writer, "  switch (address) {{")?;
+    for entry in call_graph.functions.keys() {
+        writeln!(
+            writer,
+            "    case {entry}: {}(); return;",
+            function_name(*entry, symbols)
+        )?;
+    }
+    writeln!(
+        writer,
+        "    default: fprintf(stderr, \"Error! No function at %d\\n\", address); exit(-1);"
+    )?;
+    writeln!(writer, "  }}")?;
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+fn write_nodes(
+    data: &[u16],
+    functions: &BTreeMap<usize, Function>,
+    symbols: &Symbols,
+    nodes: &[Node],
+    depth: usize,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    use std::fmt::Write;
+    let indent = "  ".repeat(depth);
+    for (i, node) in nodes.iter().enumerate() {
+        match node {
+            // a label needs a statement after it, even at the end of a block
+            Node::Label(label) if i + 1 == nodes.len() => {
+                writeln!(writer, "{indent}{}: ;", symbols.label(*label))?
             }
-            Opcode::Call => {
-                let arg = data[pointer];
-                pointer += 1;
-                labels_to_print.push(arg as usize);
+            Node::Label(label) => writeln!(writer, "{indent}{}:", symbols.label(*label))?,
+            Node::Code { from, to } => write_code(data, *from, *to, &indent, writer)?,
+            Node::Call(site) => match data[site + 1] {
+                target if functions.contains_key(&(target as usize)) => writeln!(
+                    writer,
+                    "{indent}{}();",
+                    function_name(target as usize, symbols)
+                )?,
+                target => writeln!(writer, "{indent}call({});", to_dump_var(target))?,
+            },
+            Node::Jump(site) => {
+                // jumping to another routine is a tail call
+                let target = if data[*site] == Opcode::Jmp.code() {
+                    data[site + 1]
+                } else {
+                    data[site + 2]
+                };
+                writeln!(writer, "{indent}call({});", to_dump_var(target))?;
+                writeln!(writer, "{indent}return;")?;
             }
-            Opcode::Jt => {
-                pointer += 1;
-                let arg = data[pointer];
-                pointer += 1;
-                labels_to_print.push(arg as usize);
+            Node::Invalid(address) => {
+                writeln!(
+                    writer,
+                    "{indent}fputs(\"Error! No code at {address}\\n\", stderr);"
+                )?;
+                writeln!(writer, "{indent}exit(-1);")?;
             }
-            Opcode::Jf => {
-                pointer += 1;
-                let arg = data[pointer];
-                pointer += 1;
-                labels_to_print.push(arg as usize);
+            Node::If { .. } => {
+                write!(writer, "{indent}")?;
+                write_if(data, functions, symbols, node, depth, writer)?;
             }
-            _ => {
-                for _ in 0..opcode.args() {
-                    pointer += 1;
+            Node::Loop { kind, body } => {
+                match kind {
+                    LoopKind::Infinite => writeln!(writer, "{indent}for (;;) {{")?,
+                    LoopKind::While(condition) => writeln!(
+                        writer,
+                        "{indent}while ({}) {{",
+                        to_condition(data, *condition)
+                    )?,
+                    LoopKind::DoWhile(_) => writeln!(writer, "{indent}do {{")?,
+                }
+                write_nodes(data, functions, symbols, body, depth + 1, writer)?;
+                match kind {
+                    LoopKind::DoWhile(condition) => writeln!(
+                        writer,
+                        "{indent}}} while ({});",
+                        to_condition(data, *condition)
+                    )?,
+                    _ => writeln!(writer, "{indent}}}")?,
                 }
             }
+            Node::Break => writeln!(writer, "{indent}break;")?,
+            Node::Continue => writeln!(writer, "{indent}continue;")?,
+            Node::Goto(label) => writeln!(writer, "{indent}goto {};", symbols.label(*label))?,
+            Node::Return => writeln!(writer, "{indent}return;")?,
+            Node::Halt => writeln!(writer, "{indent}exit(0);")?,
         }
     }
+    Ok(())
+}
 
-    let mut all_labels = vec![];
-    let mut string_content = String::new();
-    render_c_to_string_extended(
-        data,
-        0,
-        data.len(),
-        &mut string_content,
-        &mut all_labels,
-        &mut labels_to_print,
-    )
-    .unwrap();
-    writeln!(writer, "{string_content}")?;
+/// Writes an `if` starting at the current column, chaining `else if` for a lone nested `if`.
+fn write_if(
+    data: &[u16],
+    functions: &BTreeMap<usize, Function>,
+    symbols: &Symbols,
+    node: &Node,
+    depth: usize,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    use std::fmt::Write;
+    let Node::If {
+        condition,
+        then,
+        otherwise,
+    } = node
+    else {
+        unreachable!()
+    };
+    let indent = "  ".repeat(depth);
+    let condition = to_condition(data, *condition);
+    if otherwise.is_empty()
+        && let [jump @ (Node::Break | Node::Continue | Node::Goto(_) | Node::Return)] =
+            then.as_slice()
+    {
+        let mut statement = String::new();
+        write_nodes(
+            data,
+            functions,
+            symbols,
+            std::slice::from_ref(jump),
+            0,
+            &mut statement,
+        )?;
+        write!(writer, "if ({condition}) {statement}")?;
+        return Ok(());
+    }
+    writeln!(writer, "if ({condition}) {{")?;
+    write_nodes(data, functions, symbols, then, depth + 1, writer)?;
+    match otherwise.as_slice() {
+        [] => writeln!(writer, "{indent}}}")?,
+        [nested @ Node::If { .. }] => {
+            write!(writer, "{indent}}} else ")?;
+            write_if(data, functions, symbols, nested, depth, writer)?;
+        }
+        _ => {
+            writeln!(writer, "{indent}}} else {{")?;
+            write_nodes(data, functions, symbols, otherwise, depth + 1, writer)?;
+            writeln!(writer, "{indent}}}")?;
+        }
+    }
+    Ok(())
+}
 
-    writeln!(writer, "")?;
-    writeln!(writer, "  labels:")?;
-    writeln!(writer, "  switch (label_to_go) {{")?;
-    for x in &all_labels {
-        writeln!(writer, "    case {x}: goto _{x};")?;
+/// The symbol name of a function, or `f_entry` if it has none.
+fn function_name(entry: usize, symbols: &Symbols) -> String {
+    match symbols.get(entry) {
+        Some(symbol) => symbol.name.clone(),
+        None => format!("f_{entry}"),
     }
-    writeln!(writer, "  }}")?;
+}
 
-    writeln!(writer, "}}")?;
-    writer.flush()?;
-    Ok(())
+fn to_condition(data: &[u16], condition: Condition) -> String {
+    let a = to_dump_var(data[condition.site + 1]);
+    let jumps_if_zero = data[condition.site] == Opcode::Jf.code();
+    if jumps_if_zero != condition.negate {
+        format!("{a} == 0")
+    } else {
+        format!("{a} != 0")
+    }
 }
 
-pub fn render_c_to_string(
+/// Writes straight-line instructions, printing runs of literal `out`s as one string.
+fn write_code(
     data: &[u16],
     from: usize,
     to: usize,
-) -> Result<String, std::fmt::Error> {
-    let mut all_labels = vec![];
-    let mut labels_to_print = vec![];
-    let mut string_content = String::new();
-    render_c_to_string_extended(data, from, to, &mut string_content, &mut all_labels, &mut labels_to_print)?;
-    Ok(string_content)
+    indent: &str,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    use std::fmt::Write;
+    let mut string_to_print = String::new();
+    let mut pointer = from;
+    while pointer < to {
+        let Some(opcode) = decode(data, pointer, to) else {
+            writeln!(
+                writer,
+                "{indent}// {pointer} // unknown data {}",
+                data[pointer]
+            )?;
+            pointer += 1;
+            continue;
+        };
+        if opcode == Opcode::Out && data[pointer + 1] < 32768 {
+            string_to_print.push(data[pointer + 1] as u8 as char);
+        } else {
+            if !string_to_print.is_empty() {
+                write_fwrite(writer, indent, &std::mem::take(&mut string_to_print))?;
+            }
+            match to_statement(data, pointer, opcode) {
+                Some(statement) => writeln!(writer, "{indent}{statement}")?,
+                None => {
+                    let args: Vec<String> = data[pointer + 1..pointer + 1 + opcode.args()]
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect();
+                    writeln!(
+                        writer,
+                        "{indent}//invalid {}? {pointer} // {opcode:?} {}",
+                        opcode.name(),
+                        args.join(" ")
+                    )?;
+                }
+            }
+        }
+        pointer += 1 + opcode.args();
+    }
+    if !string_to_print.is_empty() {
+        write_fwrite(writer, indent, &string_to_print)?;
+    }
+    Ok(())
+}
+
+/// C for an instruction that does not transfer control, or `None` if it writes to a literal.
+fn to_statement(data: &[u16], pointer: usize, opcode: Opcode) -> Option<String> {
+    let arg = |i: usize| {
+        data.get(pointer + 1 + i)
+            .map(|a| to_dump_var(*a))
+            .unwrap_or_default()
+    };
+    let (a, b, c) = (arg(0), arg(1), arg(2));
+    let writes_register = !matches!(
+        opcode,
+        Opcode::Noop | Opcode::Push | Opcode::Wmem | Opcode::Out
+    );
+    if writes_register && data[pointer + 1] < 32768 {
+        return None;
+    }
+    Some(match opcode {
+        Opcode::Noop => ";".to_string(),
+        Opcode::Set => format!("{a} = {b};"),
+        Opcode::Push => format!("push_stack({a});"),
+        Opcode::Pop => format!("{a} = pop_stack();"),
+        Opcode::Eq => format!("{a} = {b} == {c};"),
+        Opcode::Gt => format!("{a} = {b} > {c};"),
+        Opcode::Add => format!("{a} = ({b} + {c}) % 32768;"),
+        Opcode::Mult => format!("{a} = ((unsigned int) {b} * {c}) % 32768;"),
+        Opcode::Mod => format!("{a} = {b} % {c};"),
+        Opcode::And => format!("{a} = {b} & {c};"),
+        Opcode::Or => format!("{a} = {b} | {c};"),
+        Opcode::Not => format!("{a} = ~{b} & 32767;"),
+        Opcode::Rmem => format!("{a} = mem[{b}];"),
+        Opcode::Wmem => format!("write_mem({a}, {b});"),
+        Opcode::Out => format!("putchar({a});"),
+        Opcode::In => format!("{a} = read_char();"),
+        _ => unreachable!("{opcode:?} transfers control"),
+    })
+}
+
+/// Every instruction gets a label so that `ret` and indirect jumps can reach it through the
+/// `labels` switch, except an `out` continuing a run of `out`s, which is printed as one string.
+pub fn plan_labels(
+    data: &[u16],
+    instructions: &BTreeMap<usize, Opcode>,
+    code_map: &CodeMap,
+) -> BTreeSet<usize> {
+    let mut targets: BTreeSet<usize> = code_map.targets.clone();
+    for (pointer, opcode) in instructions {
+        match opcode {
+            Opcode::Jmp | Opcode::Call => targets.insert(data[pointer + 1] as usize),
+            Opcode::Jt | Opcode::Jf => targets.insert(data[pointer + 2] as usize),
+            _ => false,
+        };
+    }
+    let mut labels = BTreeSet::new();
+    let mut run_end = None;
+    for (pointer, opcode) in instructions {
+        let literal_out = *opcode == Opcode::Out && data[pointer + 1] < 32768;
+        if !(literal_out && run_end == Some(*pointer)) || targets.contains(pointer) {
+            labels.insert(*pointer);
+        }
+        run_end = literal_out.then_some(pointer + 2);
+    }
+    labels
+}
+
+/// Maps every label to the end of the straight-line code emitted under it, which is what its
+/// stale guard has to cover.
+pub fn plan_regions(
+    instructions: &BTreeMap<usize, Opcode>,
+    labels: &BTreeSet<usize>,
+) -> BTreeMap<usize, usize> {
+    let mut regions = BTreeMap::new();
+    let mut current = None;
+    for (pointer, opcode) in instructions {
+        if labels.contains(pointer) {
+            current = Some(*pointer);
+        }
+        if let Some(label) = current {
+            regions.insert(label, pointer + 1 + opcode.args());
+        }
+    }
+    regions
 }
 
 fn render_c_to_string_extended(
@@ -133,10 +602,15 @@ fn render_c_to_string_extended(
     from: usize,
     to: usize,
     writer: &mut String,
-    all_labels: &mut Vec<usize>,
-    labels_to_print: &mut Vec<usize>,
+    all_labels: &mut Vec<(usize, usize)>,
+    code_map: &CodeMap,
+    symbols: &Symbols,
 ) -> Result<(), std::fmt::Error> {
     use std::fmt::Write;
+    let instructions = plan_instructions(data, from, to, code_map);
+    let labels = plan_labels(data, &instructions, code_map);
+    let regions = plan_regions(&instructions, &labels);
+
     let mut pointer = from;
     let mut string_to_print = String::new();
     loop {
@@ -144,283 +618,181 @@ fn render_c_to_string_extended(
             break;
         }
 
-        let mut label_printed = false;
-        if labels_to_print.contains(&pointer) {
-            writeln!(writer, "  _{}:", pointer)?;
-            all_labels.push(pointer);
-            label_printed = true;
-            let (i, _) = labels_to_print
-                .iter()
-                .enumerate()
-                .find(|(_, p)| **p == pointer)
-                .unwrap();
-            labels_to_print.remove(i);
-        }
-
-        // if pointer/2 ==380 {
-        //     println!("test");
-        // }
-
-        let code = data[pointer];
         pointer += 1;
 
-        if code != 19 && string_to_print.len() > 0 {
-            writeln!(writer, "  printf(\"{string_to_print}\");")?;
+        let opcode = instructions.get(&(pointer - 1)).copied();
+        if !string_to_print.is_empty()
+            && (opcode != Some(Opcode::Out) || labels.contains(&(pointer - 1)))
+        {
+            write_fwrite(writer, "  ", &string_to_print)?;
             string_to_print.clear();
         }
 
-        if code > 21 {
-            writeln!(writer, "  // {} // unknown data {code:?}", pointer - 1)?;
+        let Some(opcode) = opcode else {
+            // data is only in `mem`; a run of it gets one comment
+            let end = instructions
+                .range(pointer..to)
+                .next()
+                .map_or(to, |(next, _)| *next);
+            writeln!(writer, "  // {}..{end}: data", pointer - 1)?;
+            pointer = end;
             continue;
+        };
+        if let Some(end) = regions.get(&(pointer - 1)) {
+            match symbols.get(pointer - 1).and_then(|s| s.comment.as_ref()) {
+                Some(comment) => {
+                    writeln!(writer, "  {}: // {comment}", symbols.label(pointer - 1))?
+                }
+                None => writeln!(writer, "  {}:", symbols.label(pointer - 1))?,
+            }
+            writeln!(
+                writer,
+                "  if (stale_count && is_stale({}, {end})) {{ label_to_go = {}; goto interpret; }}",
+                pointer - 1,
+                pointer - 1
+            )?;
+            all_labels.push((pointer - 1, *end));
         }
-        // if code != 19 {
-        // }
-        if !label_printed {
-            writeln!(writer, "  _{}:", pointer - 1)?;
-            all_labels.push(pointer - 1);
-        }
-        let opcode = Opcode::of(code);
-        // println!("{code} {opcode:?}");
+        let next = pointer + opcode.args();
 
         match opcode {
             Opcode::Halt => {
                 writeln!(writer, "  exit(0);")?;
             }
-            // Opcode::Noop => {}
-            Opcode::Set => {
-                let r = data[pointer];
-                if r < 32768 {
-                    write!(writer, "  //invalid set? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let r = crate::to_index(r);
-                pointer += 1;
-
-                let a = data[pointer];
-                pointer += 1;
-                let a = to_dump_var(a);
-                writeln!(writer, "  r{r} = {a};")?;
-            }
             Opcode::Jmp => {
-                // writeln!(writer, "  // {} // {opcode:?}", pointer)?;
                 let arg = data[pointer];
                 pointer += 1;
-                writeln!(writer, "  goto _{};", { arg })?;
-                // labels_to_print.push((arg) as usize);
+                writeln!(writer, "  {}", to_goto(arg, &labels, symbols))?;
             }
             Opcode::Jt => {
-                // writeln!(writer, "  // {} // {opcode:?}", pointer)?;
-
                 let a = data[pointer];
                 let a = to_dump_var(a);
                 pointer += 1;
 
                 let arg = data[pointer];
                 pointer += 1;
-                writeln!(writer, "  if ({a} != 0)  goto _{};", { arg })?;
+                writeln!(
+                    writer,
+                    "  if ({a} != 0) {{ {} }}",
+                    to_goto(arg, &labels, symbols)
+                )?;
             }
             Opcode::Jf => {
-                // writeln!(writer, "  // {} // {opcode:?}", pointer)?;
-
                 let a = data[pointer];
                 let a = to_dump_var(a);
                 pointer += 1;
 
                 let arg = data[pointer];
                 pointer += 1;
-                writeln!(writer, "  if ({a} == 0)  goto _{};", { arg })?;
-            }
-            Opcode::Add => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid add? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} + {c}) % 32768;")?;
-            }
-            Opcode::Mult => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid mult? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} * {c}) % 32768;")?;
-            }
-            Opcode::Mod => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid mod? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} % {c});")?;
-            }
-            Opcode::And => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid and? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} & {c});")?;
+                writeln!(
+                    writer,
+                    "  if ({a} == 0) {{ {} }}",
+                    to_goto(arg, &labels, symbols)
+                )?;
             }
-            Opcode::Or => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid or? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} | {c});")?;
-            }
-            Opcode::Not => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid not? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = (~{b}) & 32767;")?;
-            }
-            Opcode::Eq => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid eq? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} == {c}) ? 1 : 0;")?;
-            }
-            Opcode::Gt => {
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid gt? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                let b = to_dump_var(data[pointer]);
-                pointer += 1;
-                let c = to_dump_var(data[pointer]);
-                pointer += 1;
-                writeln!(writer, "  r{a} = ({b} > {c}) ? 1 : 0;")?;
-            }
-            Opcode::Push => {
-                // writeln!(
-                //     writer,
-                //     "  if (stack_pointer >= stack_capacity) {{ fputs(\"Error! Stack overflow!\\n\", stderr); exit(-1); }}"
-                // )?;
-                let value = to_dump_var(data[pointer]);
-                pointer += 1;
-                // writeln!(writer, "  stack[stack_pointer++] = {value};")?;
-                writeln!(writer, "  push_stack({value});")?;
-            }
-            Opcode::Pop => {
-                // writeln!(writer, "  if (stack_pointer == 0) exit(-1);")?;
-                let a = data[pointer];
-                if a < 32768 {
-                    write!(writer, "  //invalid pop? {} // {opcode:?}", pointer)?;
-                    write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
-                    continue;
-                }
-                let a = crate::to_index(a);
-                pointer += 1;
-                // writeln!(writer, "  r{a} = stack[--stack_pointer];")?;
-                writeln!(writer, "  r{a} = pop_stack();")?;
-            }
-            // Opcode::Rmem => {}
-            // Opcode::Wmem => {}
             Opcode::Call => {
                 let value = data[pointer];
                 pointer += 1;
-                // writeln!(
-                //     writer,
-                //     "  if (stack_pointer >= stack_capacity) {{ fputs(\"Error! Stack overflow!\\n\", stderr); exit(-1); }}"
-                // )?;
-                // writeln!(writer, "  stack[stack_pointer++] = {};", pointer)?;
                 writeln!(writer, "  push_stack({pointer});")?;
-
-                if value < 32768 {
-                    labels_to_print.push((value) as usize);
-                    writeln!(writer, "  goto _{value};")?;
-                } else {
-                    writeln!(writer, "  label_to_go  = r{};", value - 32768)?;
-                    writeln!(writer, "  goto labels;")?;
-                }
+                writeln!(writer, "  {}", to_goto(value, &labels, symbols))?;
+            }
+            Opcode::Ret => {
+                writeln!(writer, "  if (stack_pointer == 0) exit(0);")?;
+                writeln!(writer, "  label_to_go = pop_stack();")?;
+                writeln!(writer, "  goto labels;")?;
             }
-            // Opcode::Ret => {}
-            Opcode::Out => {
+            Opcode::Out if data[pointer] < 32768 => {
                 let arg = data[pointer];
                 pointer += 1;
+                string_to_print.push(arg as u8 as char);
                 if arg == 10 {
-                    string_to_print.push_str("\\n");
-                    writeln!(writer, "  printf(\"{string_to_print}\");")?;
+                    write_fwrite(writer, "  ", &string_to_print)?;
                     string_to_print.clear();
-                } else {
-                    string_to_print.push(arg as u8 as char);
                 }
             }
-            // Opcode::In => {}
-            _ => {
-                // write!(writer, "  // {} // {opcode:?}", pointer)?;
-                // for _ in 0..opcode.args() {
-                //     let arg = read_u16(&data[pointer..]);
-                //     pointer += 1;
-                //     // println!("arg {arg}");
-                //     write!(writer, " {arg}")?;
-                // }
-                // write!(writer, "\n")?;
-                write_commented_opcode(&data, writer, &mut pointer, &opcode)?;
+            _ => match to_statement(data, pointer - 1, opcode) {
+                Some(statement) => {
+                    pointer = next;
+                    writeln!(writer, "  {statement}")?;
+                }
+                None => {
+                    write!(
+                        writer,
+                        "  //invalid {}? {} // {opcode:?}",
+                        opcode.name(),
+                        pointer
+                    )?;
+                    write_commented_opcode(data, writer, &mut pointer, &opcode)?;
+                    continue;
+                }
+            },
+        }
+
+        // falling through into data or into an instruction that was not given a label
+        if !matches!(opcode, Opcode::Halt | Opcode::Jmp | Opcode::Ret)
+            && next < to
+            && !labels.contains(&next)
+        {
+            let continues_run =
+                instructions.get(&next) == Some(&Opcode::Out) && data[next + 1] < 32768;
+            if !continues_run {
+                writeln!(writer, "  {}", to_goto(next as u16, &labels, symbols))?;
             }
         }
     }
+    if !string_to_print.is_empty() {
+        write_fwrite(writer, "  ", &string_to_print)?;
+    }
+    Ok(())
+}
+
+/// Writes `s` with `fwrite` and its length, so that `%` is never interpreted and a NUL does not end
+/// it, splitting long text into adjacent literals of at most `MAX_LITERAL_LENGTH` characters.
+fn write_fwrite(writer: &mut String, indent: &str, s: &str) -> Result<(), std::fmt::Error> {
+    use std::fmt::Write;
+    let escaped: Vec<String> = s.chars().map(escape_c_char).collect();
+    let mut lines = vec![];
+    let mut line = String::new();
+    for c in escaped {
+        if line.len() + c.len() > MAX_LITERAL_LENGTH {
+            lines.push(std::mem::take(&mut line));
+        }
+        line.push_str(&c);
+    }
+    lines.push(line);
+    write!(writer, "{indent}fwrite(\"{}\"", lines[0])?;
+    for line in &lines[1..] {
+        write!(writer, "\n{indent}       \"{line}\"")?;
+    }
+    writeln!(writer, ", 1, {}, stdout);", s.chars().count())?;
     Ok(())
 }
 
+/// Escapes one character for a C string literal. Anything outside printable ascii becomes a
+/// three digit octal escape, which unlike `\x` cannot swallow a following digit.
+fn escape_c_char(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        // keeps `??x` from being read as a trigraph
+        '?' => "\\?".to_string(),
+        ' '..='~' => c.to_string(),
+        _ => format!("\\{:03o}", c as u32 & 0xff),
+    }
+}
+
+/// Jumps straight to a translated label, or through the `labels` switch for registers and
+/// addresses that were not translated.
+fn to_goto(target: u16, labels: &BTreeSet<usize>, symbols: &Symbols) -> String {
+    if target < 32768 && labels.contains(&(target as usize)) {
+        format!("goto {};", symbols.label(target as usize))
+    } else {
+        format!("label_to_go = {}; goto labels;", to_dump_var(target))
+    }
+}
+
 fn to_dump_var(a: u16) -> String {
     if a < 32768 {
         a.to_string()
@@ -440,9 +812,117 @@ fn write_commented_opcode(
     for _ in 0..opcode.args() {
         let arg = data[*pointer];
         *pointer += 1;
-        // println!("arg {arg}");
         write!(writer, " {arg}")?;
     }
-    write!(writer, "\n")?;
+    writeln!(writer)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the adjacent string literals following every `fwrite(` back into raw text, checking
+    /// the length passed with them.
+    fn fwrite_strings(c: &str) -> Vec<String> {
+        let mut strings = vec![];
+        for (start, _) in c.match_indices("fwrite(") {
+            let mut chars = c[start + "fwrite(".len()..].chars().peekable();
+            let mut s = String::new();
+            loop {
+                while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    chars.next();
+                }
+                if chars.peek() != Some(&'"') {
+                    break;
+                }
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next().unwrap() {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            d @ '0'..='7' => {
+                                let octal: String =
+                                    [d, chars.next().unwrap(), chars.next().unwrap()]
+                                        .iter()
+                                        .collect();
+                                s.push(u8::from_str_radix(&octal, 8).unwrap() as char);
+                            }
+                            other => s.push(other),
+                        },
+                        c => {
+                            assert!((' '..='~').contains(&c), "raw {c:?} in literal");
+                            s.push(c)
+                        }
+                    }
+                }
+            }
+            let rest: String = chars.collect();
+            let length = format!(", 1, {}, stdout);", s.chars().count());
+            assert!(rest.starts_with(&length), "{length} expected before {rest}");
+            strings.push(s);
+        }
+        strings
+    }
+
+    #[test]
+    fn special_characters_survive_escaping() {
+        for s in [
+            "say \"hi\"\n",
+            "100%d %s%n",
+            "back\\slash",
+            "tab\tend",
+            "\u{1}\u{7f}\u{0}9",
+            "??=??/",
+        ] {
+            let mut c = String::new();
+            write_fwrite(&mut c, "  ", s).unwrap();
+            assert!(c.ends_with(", stdout);\n"), "{c}");
+            assert!(!c.contains("fputs("), "{c}");
+            assert_eq!(fwrite_strings(&c), vec![s.to_string()]);
+        }
+    }
+
+    #[test]
+    fn long_strings_are_split_into_adjacent_literals() {
+        let s = "\"quoted\" ".repeat(40);
+        let mut c = String::new();
+        write_fwrite(&mut c, "  ", &s).unwrap();
+        assert!(c.lines().count() > 1);
+        assert!(
+            c.lines().all(|line| line.len() <= MAX_LITERAL_LENGTH + 16),
+            "{c}"
+        );
+        assert_eq!(fwrite_strings(&c), vec![s]);
+    }
+
+    #[test]
+    fn every_string_in_challenge_bin_round_trips() {
+        let data = crate::load_image("challenge.bin").unwrap();
+        let code_map = discover(&data, &[0]);
+        let mut expected = String::new();
+        for (pointer, opcode) in plan_instructions(&data, 0, data.len(), &code_map) {
+            if opcode == Opcode::Out && data[pointer + 1] < 32768 {
+                expected.push(data[pointer + 1] as u8 as char);
+            }
+        }
+
+        let mut c = String::new();
+        render_c_to_string_extended(
+            &data,
+            0,
+            data.len(),
+            &mut c,
+            &mut vec![],
+            &code_map,
+            &Symbols::default(),
+        )
+        .unwrap();
+        assert!(!c.contains("printf("));
+        let strings = fwrite_strings(&c);
+        assert!(strings.len() > 20);
+        assert_eq!(strings.concat(), expected);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::Opcode;
+use crate::discovery::{discover, plan_instructions};
+use crate::renderer_c::{MAX_LITERAL_LENGTH, plan_labels, plan_regions};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Machine state and the fallback interpreter of the generated program. Mirrors the C runtime:
+/// every translated label is guarded by `is_stale`, and `interpret` runs from `mem` until it
+/// reaches a label whose words are intact again.
+const RUNTIME: &str = r#"/// Runs the interpreter from `$pc`, ending `run` when the program does.
+macro_rules! interpret {
+    ($m:expr, $pc:expr) => {
+        match $m.interpret($pc)? {
+            Some(pc) => pc,
+            None => return Ok(()),
+        }
+    };
+}
+
+struct Machine<'a> {
+    r: [u16; 8],
+    mem: Vec<u16>,
+    stack: Vec<u16>,
+    translated: Vec<bool>,
+    region_end: Vec<u16>,
+    stale: Vec<bool>,
+    stale_count: usize,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+impl Machine<'_> {
+    fn value(&self, a: u16) -> u16 {
+        match a {
+            0..32768 => a,
+            32768..32776 => self.r[a as usize - 32768],
+            _ => panic!("Error! Invalid value {a}"),
+        }
+    }
+
+    fn register(&mut self, a: u16) -> &mut u16 {
+        match a {
+            32768..32776 => &mut self.r[a as usize - 32768],
+            _ => panic!("Error! Invalid register {a}"),
+        }
+    }
+
+    fn pop(&mut self) -> u16 {
+        self.stack.pop().expect("Error! Pop on empty stack!")
+    }
+
+    fn write_mem(&mut self, a: u16, b: u16) {
+        let a = a as usize;
+        if self.translated[a] && self.stale[a] != (b != IMAGE.get(a).copied().unwrap_or(0)) {
+            self.stale[a] = !self.stale[a];
+            if self.stale[a] {
+                self.stale_count += 1;
+            } else {
+                self.stale_count -= 1;
+            }
+        }
+        self.mem[a] = b;
+    }
+
+    fn is_stale(&self, from: u16, to: u16) -> bool {
+        self.stale[from as usize..to as usize].contains(&true)
+    }
+
+    /// Reads one character, or `None` at the end of the input.
+    fn read(&mut self) -> std::io::Result<Option<u16>> {
+        self.output.flush()?;
+        let mut c = [0];
+        Ok(match self.input.read(&mut c)? {
+            0 => None,
+            _ => Some(c[0] as u16),
+        })
+    }
+
+    /// Runs from `mem` until `pc` is a label whose words are intact. `None` means the program
+    /// ended.
+    fn interpret(&mut self, mut pc: u16) -> std::io::Result<Option<u16>> {
+        loop {
+            let end = self.region_end[pc as usize];
+            if end != 0 && !self.is_stale(pc, end) {
+                return Ok(Some(pc));
+            }
+            let arg = |i: u16| self.mem[(pc.wrapping_add(i) & 32767) as usize];
+            let (a, b, c) = (arg(1), arg(2), arg(3));
+            match self.mem[pc as usize] {
+                0 => return Ok(None),
+                1 => { *self.register(a) = self.value(b); pc += 3; }
+                2 => { let a = self.value(a); self.stack.push(a); pc += 2; }
+                3 => { *self.register(a) = self.pop(); pc += 2; }
+                4 => { *self.register(a) = (self.value(b) == self.value(c)) as u16; pc += 4; }
+                5 => { *self.register(a) = (self.value(b) > self.value(c)) as u16; pc += 4; }
+                6 => pc = self.value(a),
+                7 => pc = if self.value(a) != 0 { self.value(b) } else { pc + 3 },
+                8 => pc = if self.value(a) == 0 { self.value(b) } else { pc + 3 },
+                9 => { *self.register(a) = (self.value(b) + self.value(c)) % 32768; pc += 4; }
+                10 => {
+                    let product = self.value(b) as u32 * self.value(c) as u32;
+                    *self.register(a) = (product % 32768) as u16;
+                    pc += 4;
+                }
+                11 => { *self.register(a) = self.value(b) % self.value(c); pc += 4; }
+                12 => { *self.register(a) = self.value(b) & self.value(c); pc += 4; }
+                13 => { *self.register(a) = self.value(b) | self.value(c); pc += 4; }
+                14 => { *self.register(a) = !self.value(b) & 32767; pc += 3; }
+                15 => { *self.register(a) = self.mem[self.value(b) as usize]; pc += 3; }
+                16 => { self.write_mem(self.value(a), self.value(b)); pc += 3; }
+                17 => { self.stack.push(pc + 2); pc = self.value(a); }
+                18 => match self.stack.pop() {
+                    Some(address) => pc = address,
+                    None => return Ok(None),
+                },
+                19 => { self.output.write_all(&[self.value(a) as u8])?; pc += 2; }
+                20 => match self.read()? {
+                    Some(c) => { *self.register(a) = c; pc += 2; }
+                    None => return Ok(None),
+                },
+                21 => pc += 1,
+                op => panic!("Error! Unknown opcode {op} at {pc}"),
+            }
+            pc &= 32767;
+        }
+    }
+}
+"#;
+
+/// Translates the image to a standalone Rust program with the same structure as the C backend.
+///
+/// Every label of [`crate::renderer_c::render`] becomes an arm of one `match pc`, and a jump
+/// assigns `pc` and goes round the loop again. Addresses without an arm, such as most indirect
+/// targets, and overwritten code are run by the embedded interpreter. The program exposes
+/// `run(input, output)` and a `main` that plays on stdin and stdout, so it builds with plain
+/// `rustc -O`.
+pub fn render(data: &[u16], name: &str) -> std::io::Result<()> {
+    let mut writer = String::new();
+    render_to_string(data, &mut writer).unwrap();
+    std::fs::write(name, writer)
+}
+
+fn render_to_string(data: &[u16], writer: &mut String) -> Result<(), std::fmt::Error> {
+    let code_map = discover(data, &[0]);
+    let instructions = plan_instructions(data, 0, data.len(), &code_map);
+    let labels = plan_labels(data, &instructions, &code_map);
+    let regions = plan_regions(&instructions, &labels);
+
+    writeln!(writer, "use std::io::{{Read, Write}};")?;
+    writeln!(writer)?;
+    writeln!(writer, "static IMAGE: [u16; {}] = [", data.len())?;
+    for chunk in data.chunks(16) {
+        let line: Vec<String> = chunk.iter().map(|w| w.to_string()).collect();
+        writeln!(writer, "    {},", line.join(", "))?;
+    }
+    writeln!(writer, "];")?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "/// Translated labels with the end of the straight-line code under each."
+    )?;
+    writeln!(
+        writer,
+        "static REGIONS: [(u16, u16); {}] = [",
+        regions.len()
+    )?;
+    let regions_list: Vec<(usize, usize)> = regions.iter().map(|(l, e)| (*l, *e)).collect();
+    for chunk in regions_list.chunks(8) {
+        let line: Vec<String> = chunk
+            .iter()
+            .map(|(start, end)| format!("({start}, {end})"))
+            .collect();
+        writeln!(writer, "    {},", line.join(", "))?;
+    }
+    writeln!(writer, "];")?;
+    writeln!(writer)?;
+    writeln!(writer, "{RUNTIME}")?;
+
+    writeln!(
+        writer,
+        "pub fn run(input: &mut dyn Read, output: &mut dyn Write) -> std::io::Result<()> {{"
+    )?;
+    writeln!(writer, "    let mut mem = vec![0; 32768];")?;
+    writeln!(writer, "    mem[..IMAGE.len()].copy_from_slice(&IMAGE);")?;
+    writeln!(writer, "    let mut m = Machine {{")?;
+    writeln!(writer, "        r: [0; 8],")?;
+    writeln!(writer, "        mem,")?;
+    writeln!(writer, "        stack: vec![],")?;
+    writeln!(writer, "        translated: vec![false; 32768],")?;
+    writeln!(writer, "        region_end: vec![0; 32768],")?;
+    writeln!(writer, "        stale: vec![false; 32768],")?;
+    writeln!(writer, "        stale_count: 0,")?;
+    writeln!(writer, "        input,")?;
+    writeln!(writer, "        output,")?;
+    writeln!(writer, "    }};")?;
+    writeln!(writer, "    for (start, end) in REGIONS {{")?;
+    writeln!(writer, "        m.region_end[start as usize] = end;")?;
+    writeln!(
+        writer,
+        "        m.translated[start as usize..end as usize].fill(true);"
+    )?;
+    writeln!(writer, "    }}")?;
+    writeln!(writer, "    let mut pc: u16 = 0;")?;
+    writeln!(writer, "    loop {{")?;
+    writeln!(writer, "        match pc {{")?;
+    for (label, end) in &regions {
+        writeln!(writer, "            {label} => {{")?;
+        writeln!(
+            writer,
+            "                if m.stale_count > 0 && m.is_stale({label}, {end}) {{"
+        )?;
+        writeln!(writer, "                    pc = interpret!(m, {label});")?;
+        writeln!(writer, "                    continue;")?;
+        writeln!(writer, "                }}")?;
+        write_region(data, *label, *end, &instructions, writer)?;
+        writeln!(writer, "            }}")?;
+    }
+    writeln!(writer, "            _ => pc = interpret!(m, pc),")?;
+    writeln!(writer, "        }}")?;
+    writeln!(writer, "    }}")?;
+    writeln!(writer, "}}")?;
+    writeln!(writer)?;
+    writeln!(writer, "fn main() -> std::io::Result<()> {{")?;
+    writeln!(
+        writer,
+        "    let mut output = std::io::BufWriter::new(std::io::stdout().lock());"
+    )?;
+    writeln!(
+        writer,
+        "    run(&mut std::io::stdin().lock(), &mut output)?;"
+    )?;
+    writeln!(writer, "    output.flush()")?;
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+/// Writes the straight-line code of one label, ending every path with a new `pc`.
+fn write_region(
+    data: &[u16],
+    label: usize,
+    end: usize,
+    instructions: &BTreeMap<usize, Opcode>,
+    writer: &mut String,
+) -> Result<(), std::fmt::Error> {
+    const INDENT: &str = "                ";
+    let mut bytes_to_print = vec![];
+    let mut pointer = label;
+    while pointer < end {
+        let opcode = instructions[&pointer];
+        let next = pointer + 1 + opcode.args();
+        let arg = |i: usize| data[pointer + 1 + i];
+        let value = |i: usize| to_value(arg(i));
+        if opcode == Opcode::Out && arg(0) < 32768 {
+            bytes_to_print.push(arg(0) as u8);
+            pointer = next;
+            continue;
+        }
+        if !bytes_to_print.is_empty() {
+            write_bytes(writer, INDENT, &std::mem::take(&mut bytes_to_print))?;
+        }
+
+        let writes_register = !matches!(
+            opcode,
+            Opcode::Halt
+                | Opcode::Noop
+                | Opcode::Push
+                | Opcode::Jmp
+                | Opcode::Jt
+                | Opcode::Jf
+                | Opcode::Wmem
+                | Opcode::Call
+                | Opcode::Ret
+                | Opcode::Out
+        );
+        if writes_register && arg(0) < 32768 {
+            let args: Vec<String> = data[pointer + 1..next]
+                .iter()
+                .map(|a| a.to_string())
+                .collect();
+            writeln!(
+                writer,
+                "{INDENT}// invalid {}? {pointer} {}",
+                opcode.name(),
+                args.join(" ")
+            )?;
+            pointer = next;
+            continue;
+        }
+        let a = value(0);
+        match opcode {
+            Opcode::Halt => writeln!(writer, "{INDENT}return Ok(());")?,
+            Opcode::Noop => {}
+            Opcode::Set => writeln!(writer, "{INDENT}{a} = {};", value(1))?,
+            Opcode::Push => writeln!(writer, "{INDENT}m.stack.push({});", value(0))?,
+            Opcode::Pop => writeln!(writer, "{INDENT}{a} = m.pop();")?,
+            Opcode::Eq => writeln!(
+                writer,
+                "{INDENT}{a} = ({} == {}) as u16;",
+                value(1),
+                value(2)
+            )?,
+            Opcode::Gt => writeln!(
+                writer,
+                "{INDENT}{a} = ({} > {}) as u16;",
+                value(1),
+                value(2)
+            )?,
+            Opcode::Jmp => writeln!(writer, "{INDENT}pc = {}; continue;", value(0))?,
+            Opcode::Jt => writeln!(
+                writer,
+                "{INDENT}if {} != 0 {{ pc = {}; continue; }}",
+                value(0),
+                value(1)
+            )?,
+            Opcode::Jf => writeln!(
+                writer,
+                "{INDENT}if {} == 0 {{ pc = {}; continue; }}",
+                value(0),
+                value(1)
+            )?,
+            Opcode::Add => writeln!(
+                writer,
+                "{INDENT}{a} = ({} + {}) % 32768;",
+                value(1),
+                value(2)
+            )?,
+            Opcode::Mult => writeln!(
+                writer,
+                "{INDENT}{a} = (({} as u32 * {} as u32) % 32768) as u16;",
+                value(1),
+                value(2)
+            )?,
+            Opcode::Mod => writeln!(writer, "{INDENT}{a} = {} % {};", value(1), value(2))?,
+            Opcode::And => writeln!(writer, "{INDENT}{a} = {} & {};", value(1), value(2))?,
+            Opcode::Or => writeln!(writer, "{INDENT}{a} = {} | {};", value(1), value(2))?,
+            Opcode::Not => writeln!(writer, "{INDENT}{a} = !{} & 32767;", value(1))?,
+            Opcode::Rmem => writeln!(writer, "{INDENT}{a} = m.mem[{} as usize];", value(1))?,
+            Opcode::Wmem => writeln!(writer, "{INDENT}m.write_mem({}, {});", value(0), value(1))?,
+            Opcode::Call => {
+                writeln!(writer, "{INDENT}m.stack.push({next});")?;
+                writeln!(writer, "{INDENT}pc = {}; continue;", value(0))?;
+            }
+            Opcode::Ret => {
+                writeln!(writer, "{INDENT}match m.stack.pop() {{")?;
+                writeln!(
+                    writer,
+                    "{INDENT}    Some(address) => {{ pc = address; continue; }}"
+                )?;
+                writeln!(writer, "{INDENT}    None => return Ok(()),")?;
+                writeln!(writer, "{INDENT}}}")?;
+            }
+            Opcode::Out => writeln!(
+                writer,
+                "{INDENT}m.output.write_all(&[{} as u8])?;",
+                value(0)
+            )?,
+            Opcode::In => {
+                writeln!(writer, "{INDENT}match m.read()? {{")?;
+                writeln!(writer, "{INDENT}    Some(c) => {a} = c,")?;
+                writeln!(writer, "{INDENT}    None => return Ok(()),")?;
+                writeln!(writer, "{INDENT}}}")?;
+            }
+        }
+        pointer = next;
+    }
+    if !bytes_to_print.is_empty() {
+        write_bytes(writer, INDENT, &bytes_to_print)?;
+    }
+
+    let last = instructions.range(..end).next_back().map(|(p, o)| (*p, *o));
+    if !matches!(
+        last,
+        Some((_, Opcode::Halt | Opcode::Jmp | Opcode::Call | Opcode::Ret))
+    ) {
+        // the next label, or the interpreter when the code runs into data
+        writeln!(writer, "{INDENT}pc = {end};")?;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` as byte string literals of at most `MAX_LITERAL_LENGTH` characters each.
+fn write_bytes(writer: &mut String, indent: &str, bytes: &[u8]) -> Result<(), std::fmt::Error> {
+    let escaped: Vec<String> = bytes
+        .iter()
+        .map(|b| std::ascii::escape_default(*b).to_string())
+        .collect();
+    let mut line = String::new();
+    for c in escaped {
+        if line.len() + c.len() > MAX_LITERAL_LENGTH {
+            writeln!(
+                writer,
+                "{indent}m.output.write_all(b\"{}\")?;",
+                std::mem::take(&mut line)
+            )?;
+        }
+        line.push_str(&c);
+    }
+    writeln!(writer, "{indent}m.output.write_all(b\"{line}\")?;")?;
+    Ok(())
+}
+
+fn to_value(a: u16) -> String {
+    if a < 32768 {
+        a.to_string()
+    } else {
+        format!("m.r[{}]", a - 32768)
+    }
+}
@@ -0,0 +1,143 @@
+use crate::vm::{Stop, Vm};
+use std::fmt;
+use std::ops::Range;
+
+/// Upper bound for [`boot`], far above what the game needs to reach its first prompt.
+const MAX_BOOT_STEPS: u64 = 1_000_000_000;
+
+/// Unchanged words that still join two changed ranges in [`changed_ranges`].
+const MAX_GAP: usize = 16;
+
+/// Where [`boot`] stops the machine.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Until {
+    /// The first `in` that finds no input left.
+    Input,
+    /// The first time execution reaches this address.
+    Address(usize),
+}
+
+impl Until {
+    /// Parses `in` or an address.
+    pub fn parse(text: &str) -> Option<Until> {
+        match text {
+            "in" => Some(Until::Input),
+            address => address.parse().ok().map(Until::Address),
+        }
+    }
+}
+
+impl fmt::Display for Until {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Until::Input => write!(f, "an input prompt"),
+            Until::Address(address) => write!(f, "{address}"),
+        }
+    }
+}
+
+/// Runs `image` with `input` queued until it reaches `until`, so that analyses see memory after
+/// the game has decrypted itself.
+pub fn boot(image: &[u16], until: Until, input: &str) -> Result<Vm, Box<dyn std::error::Error>> {
+    let mut vm = Vm::new(image)?;
+    vm.push_input(input);
+    if let Until::Address(address) = until {
+        vm.breakpoints.insert(address);
+    }
+    match vm.run(MAX_BOOT_STEPS)? {
+        Stop::NeedsInput if until == Until::Input => Ok(vm),
+        Stop::Breakpoint => Ok(vm),
+        stop => Err(format!("stopped at {} ({stop:?}) before reaching {until}", vm.pc).into()),
+    }
+}
+
+/// Live memory as an image, without the zeros past the end of the original image.
+pub fn memory_image(vm: &Vm, image_length: usize) -> Vec<u16> {
+    let used = vm
+        .mem
+        .iter()
+        .rposition(|w| *w != 0)
+        .map_or(0, |last| last + 1);
+    vm.mem[..used.max(image_length)].to_vec()
+}
+
+/// Ranges of words that differ between two images, missing words counting as zero. Ranges less
+/// than `MAX_GAP` words apart are joined.
+pub fn changed_ranges(before: &[u16], after: &[u16]) -> Vec<Range<usize>> {
+    let word = |image: &[u16], i: usize| image.get(i).copied().unwrap_or(0);
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for i in 0..before.len().max(after.len()) {
+        if word(before, i) == word(after, i) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if i - range.end < MAX_GAP => range.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// Describes where the machine stopped and which memory differs from the static image.
+pub fn to_text(image: &[u16], vm: &Vm) -> String {
+    let mut text = format!("stopped at {} after {} steps\n", vm.pc, vm.steps);
+    let registers: Vec<String> = vm.registers.iter().map(|r| r.to_string()).collect();
+    text.push_str(&format!("registers: {}\n", registers.join(" ")));
+    text.push_str(&format!("stack: {} words\n", vm.stack.len()));
+    for range in changed_ranges(image, &vm.mem) {
+        let changed = range
+            .clone()
+            .filter(|i| image.get(*i).copied().unwrap_or(0) != vm.mem[*i])
+            .count();
+        text.push_str(&format!(
+            "changed {}..{}: {changed} words\n",
+            range.start, range.end
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const PROGRAM: &str = "
+        wmem 100, 7
+        in r0
+        halt
+    ";
+
+    #[test]
+    fn boot_stops_at_the_first_prompt_or_address() {
+        let image = assemble(PROGRAM).unwrap();
+        let vm = boot(&image, Until::Input, "").unwrap();
+        assert_eq!((vm.pc, vm.mem[100]), (3, 7));
+        assert_eq!(memory_image(&vm, image.len()).len(), 101);
+        assert_eq!(
+            to_text(&image, &vm),
+            "stopped at 3 after 1 steps\nregisters: 0 0 0 0 0 0 0 0\nstack: 0 words\n\
+             changed 100..101: 1 words\n"
+        );
+
+        let vm = boot(&image, Until::Address(5), "x").unwrap();
+        assert_eq!((vm.pc, vm.registers[0]), (5, 'x' as u16));
+        assert!(boot(&image, Until::Input, "x").is_err());
+        assert!(boot(&image, Until::Address(99), "x").is_err());
+        assert_eq!(Until::parse("in"), Some(Until::Input));
+        assert_eq!(Until::parse("1820"), Some(Until::Address(1820)));
+        assert_eq!(Until::parse("out"), None);
+    }
+
+    #[test]
+    fn changes_close_together_join_into_one_range() {
+        let before = vec![0; 70];
+        let mut after = before.clone();
+        after[1] = 1;
+        after[2] = 1;
+        after[10] = 1;
+        after[40] = 1;
+        after.push(1);
+        assert_eq!(changed_ranges(&before, &after), vec![1..11, 40..41, 70..71]);
+    }
+}
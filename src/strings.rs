@@ -0,0 +1,172 @@
+use crate::Opcode;
+use crate::discovery::{discover, entry_points, sweep_instructions};
+use std::fmt::Write;
+
+/// Shortest text reported, to keep numbers that happen to look like characters out.
+pub const MIN_LENGTH: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StringKind {
+    /// A length word followed by one character per word, as printed by the game's text routines.
+    Record,
+    /// Consecutive `out` instructions with literal characters.
+    Out,
+}
+
+impl StringKind {
+    fn name(&self) -> &'static str {
+        match self {
+            StringKind::Record => "record",
+            StringKind::Out => "out",
+        }
+    }
+}
+
+pub struct GameString {
+    pub address: usize,
+    pub kind: StringKind,
+    pub text: String,
+}
+
+/// Finds the text in an image, ordered by address.
+///
+/// Records are only readable once the game has decrypted them, so pass booted memory to see
+/// more than the boot messages.
+pub fn find_strings(data: &[u16]) -> Vec<GameString> {
+    let mut strings = vec![];
+    let mut pointer = 0;
+    while pointer < data.len() {
+        let length = data[pointer] as usize;
+        let end = pointer + 1 + length;
+        if length >= MIN_LENGTH
+            && end <= data.len()
+            && data[pointer + 1..end].iter().all(|w| is_text(*w))
+        {
+            strings.push(GameString {
+                address: pointer,
+                kind: StringKind::Record,
+                text: data[pointer + 1..end]
+                    .iter()
+                    .map(|w| *w as u8 as char)
+                    .collect(),
+            });
+            pointer = end;
+        } else {
+            pointer += 1;
+        }
+    }
+
+    let code_map = discover(data, &entry_points(data));
+    let mut run: Option<GameString> = None;
+    for (pointer, opcode) in sweep_instructions(data, &code_map) {
+        let continues = run
+            .as_ref()
+            .is_some_and(|r| r.address + 2 * r.text.len() == pointer);
+        if !continues && let Some(done) = run.take().filter(|r| r.text.len() >= MIN_LENGTH) {
+            strings.push(done);
+        }
+        if opcode != Opcode::Out || !is_text(data[pointer + 1]) {
+            continue;
+        }
+        run.get_or_insert_with(|| GameString {
+            address: pointer,
+            kind: StringKind::Out,
+            text: String::new(),
+        })
+        .text
+        .push(data[pointer + 1] as u8 as char);
+    }
+    strings.extend(run.filter(|r| r.text.len() >= MIN_LENGTH));
+
+    strings.sort_by_key(|s| s.address);
+    strings
+}
+
+fn is_text(w: u16) -> bool {
+    matches!(w, 32..=126 | 10)
+}
+
+pub fn to_text(strings: &[GameString]) -> Result<String, std::fmt::Error> {
+    let mut writer = String::new();
+    for s in strings {
+        writeln!(
+            writer,
+            "{:5}: {:<6} \"{}\"",
+            s.address,
+            s.kind.name(),
+            crate::disasm::escape_string(&s.text)
+        )?;
+    }
+    Ok(writer)
+}
+
+pub fn to_json(strings: &[GameString]) -> Result<String, std::fmt::Error> {
+    let mut writer = String::new();
+    writeln!(writer, "[")?;
+    for (i, s) in strings.iter().enumerate() {
+        let separator = if i + 1 < strings.len() { "," } else { "" };
+        writeln!(
+            writer,
+            "  {{\"address\": {}, \"kind\": \"{}\", \"text\": \"{}\"}}{separator}",
+            s.address,
+            s.kind.name(),
+            escape_json(&s.text)
+        )?;
+    }
+    writeln!(writer, "]")?;
+    Ok(writer)
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 32 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn out_runs_and_records_are_found_but_short_ones_are_not() {
+        let data = assemble(
+            "
+            out \"Hi there\\n\"
+            set r0, 1
+            out \"ab\"
+            halt
+            .word 5
+            .string \"hello\"
+            .word 2
+            .string \"no\"
+            ",
+        )
+        .unwrap();
+        let strings = find_strings(&data);
+        let found: Vec<(usize, StringKind, &str)> = strings
+            .iter()
+            .map(|s| (s.address, s.kind, s.text.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (0, StringKind::Out, "Hi there\n"),
+                (26, StringKind::Record, "hello")
+            ]
+        );
+        assert_eq!(
+            to_json(&strings).unwrap(),
+            "[\n  {\"address\": 0, \"kind\": \"out\", \"text\": \"Hi there\\n\"},\n  \
+             {\"address\": 26, \"kind\": \"record\", \"text\": \"hello\"}\n]\n"
+        );
+    }
+}
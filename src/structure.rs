@@ -0,0 +1,625 @@
+use crate::Opcode;
+use crate::cfg::{Cfg, EdgeKind};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Holds when the `jt`/`jf` at `site` takes its jump, or when it falls through if `negate` is set.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub site: usize,
+    pub negate: bool,
+}
+
+impl Condition {
+    pub fn not(self) -> Condition {
+        Condition {
+            site: self.site,
+            negate: !self.negate,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoopKind {
+    Infinite,
+    /// The condition is tested before every iteration.
+    While(Condition),
+    /// The condition is tested after every iteration.
+    DoWhile(Condition),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Start of the block at this address. Only needed where some [`Node::Goto`] targets it.
+    Label(usize),
+    /// Straight-line instructions in `from..to`, none of which transfers control.
+    Code {
+        from: usize,
+        to: usize,
+    },
+    /// The `call` at this address. Execution continues after it once the callee returns.
+    Call(usize),
+    /// The `jmp`, `jt` or `jf` at this address, whose register target could not be resolved.
+    Jump(usize),
+    /// Execution runs into words at this address that were not decoded as an instruction.
+    Invalid(usize),
+    If {
+        condition: Condition,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Loop {
+        kind: LoopKind,
+        body: Vec<Node>,
+    },
+    Break,
+    Continue,
+    Goto(usize),
+    Return,
+    Halt,
+}
+
+/// Turns the blocks of one function into nested ifs and loops.
+///
+/// Loops are the natural loops of the dominator tree; each is left towards a single follow block,
+/// other exits stay `goto`s. The two arms of a branch meet again at its immediate
+/// post-dominator. Every block is emitted exactly once, so reaching a block a second time, or a
+/// block that an enclosing construct will emit later, becomes a `goto` to its label. Labels no
+/// `goto` refers to are dropped.
+pub fn structure(data: &[u16], cfg: &Cfg, blocks: &BTreeSet<usize>, entry: usize) -> Vec<Node> {
+    let successors: BTreeMap<usize, Vec<usize>> = blocks
+        .iter()
+        .map(|start| {
+            let mut targets: Vec<usize> = cfg.blocks[start]
+                .edges
+                .iter()
+                .filter(|e| {
+                    matches!(
+                        e.kind,
+                        EdgeKind::FallThrough | EdgeKind::Jump | EdgeKind::Conditional
+                    )
+                })
+                .map(|e| e.to)
+                .filter(|to| blocks.contains(to))
+                .collect();
+            targets.sort();
+            targets.dedup();
+            (*start, targets)
+        })
+        .collect();
+
+    let mut structurer = Structurer {
+        data,
+        cfg,
+        blocks,
+        loops: natural_loops(data, cfg, entry, &successors),
+        post_dominators: immediate_post_dominators(&successors),
+        emitted: BTreeSet::new(),
+        stops: vec![],
+        active: vec![],
+    };
+    let mut nodes = structurer.sequence(entry, None);
+
+    let mut targets = BTreeSet::new();
+    goto_targets(&nodes, &mut targets);
+    remove_labels(&mut nodes, &targets);
+    nodes
+}
+
+struct Structurer<'a> {
+    data: &'a [u16],
+    cfg: &'a Cfg,
+    blocks: &'a BTreeSet<usize>,
+    /// Loop headers, each with the block where execution continues once the loop is left.
+    loops: BTreeMap<usize, Option<usize>>,
+    post_dominators: BTreeMap<usize, usize>,
+    emitted: BTreeSet<usize>,
+    /// Merge points of the enclosing ifs, which are emitted once those ifs are done.
+    stops: Vec<usize>,
+    /// Headers of the enclosing loops, innermost last.
+    active: Vec<usize>,
+}
+
+impl Structurer<'_> {
+    /// Emits blocks starting at `start` until control reaches `stop` or leaves explicitly.
+    fn sequence(&mut self, start: usize, stop: Option<usize>) -> Vec<Node> {
+        let mut nodes = vec![];
+        let mut next = Some(start);
+        while let Some(block) = next {
+            if Some(block) == stop {
+                break;
+            }
+            if let Some(node) = self.escape(block) {
+                nodes.push(node);
+                break;
+            }
+            nodes.push(Node::Label(block));
+            next = if self.loops.contains_key(&block) {
+                self.emit_loop(block, &mut nodes)
+            } else {
+                self.emit_block(block, stop, &mut nodes)
+            };
+        }
+        nodes
+    }
+
+    /// The jump that reaches `block` without emitting it, if it is not to be emitted here.
+    fn escape(&self, block: usize) -> Option<Node> {
+        if let Some(header) = self.active.last() {
+            if block == *header {
+                return Some(Node::Continue);
+            }
+            if self.loops[header] == Some(block) {
+                return Some(Node::Break);
+            }
+        }
+        let enclosing = self
+            .active
+            .iter()
+            .any(|h| *h == block || self.loops[h] == Some(block));
+        if enclosing || self.emitted.contains(&block) || self.stops.contains(&block) {
+            return Some(Node::Goto(block));
+        }
+        None
+    }
+
+    fn emit_loop(&mut self, header: usize, nodes: &mut Vec<Node>) -> Option<usize> {
+        self.active.push(header);
+        let mut body = vec![];
+        if let Some(next) = self.emit_block(header, None, &mut body) {
+            body.extend(self.sequence(next, None));
+        }
+        self.active.pop();
+
+        if body.last() == Some(&Node::Continue) {
+            body.pop();
+        }
+        let kind = if let Some(condition) = body.first().and_then(breaks_if) {
+            body.remove(0);
+            LoopKind::While(condition.not())
+        } else if let Some(condition) = body.last().and_then(breaks_if)
+            && !continues(&body)
+        {
+            body.pop();
+            LoopKind::DoWhile(condition.not())
+        } else if let [.., last, Node::Break] = body.as_slice()
+            && let Some(condition) = continues_if(last)
+            && !continues(&body[..body.len() - 2])
+        {
+            body.truncate(body.len() - 2);
+            LoopKind::DoWhile(condition)
+        } else {
+            LoopKind::Infinite
+        };
+        nodes.push(Node::Loop { kind, body });
+        self.loops[&header]
+    }
+
+    /// Emits one block and returns the block that execution continues with, if any.
+    fn emit_block(
+        &mut self,
+        start: usize,
+        stop: Option<usize>,
+        nodes: &mut Vec<Node>,
+    ) -> Option<usize> {
+        self.emitted.insert(start);
+        let block = &self.cfg.blocks[&start];
+        let edge = |kind: EdgeKind| {
+            block
+                .edges
+                .iter()
+                .find(|e| e.kind == kind && self.blocks.contains(&e.to))
+                .map(|e| e.to)
+        };
+        let opcode = Opcode::of(self.data[block.last]);
+        let transfers = matches!(
+            opcode,
+            Opcode::Halt | Opcode::Ret | Opcode::Jmp | Opcode::Jt | Opcode::Jf | Opcode::Call
+        );
+        let code_end = if transfers { block.last } else { block.end };
+        if block.start < code_end {
+            nodes.push(Node::Code {
+                from: block.start,
+                to: code_end,
+            });
+        }
+
+        match opcode {
+            Opcode::Halt => nodes.push(Node::Halt),
+            Opcode::Ret => nodes.push(Node::Return),
+            Opcode::Jmp => match edge(EdgeKind::Jump) {
+                Some(target) => return Some(target),
+                None => nodes.push(Node::Jump(block.last)),
+            },
+            Opcode::Jt | Opcode::Jf => {
+                let taken = edge(EdgeKind::Conditional);
+                let fall = edge(EdgeKind::FallThrough);
+                return self.emit_branch(
+                    start,
+                    block.last,
+                    taken,
+                    fall.ok_or(block.end),
+                    stop,
+                    nodes,
+                );
+            }
+            _ => {
+                if opcode == Opcode::Call {
+                    nodes.push(Node::Call(block.last));
+                }
+                match edge(EdgeKind::FallThrough) {
+                    Some(next) => return Some(next),
+                    None => nodes.push(Node::Invalid(block.end)),
+                }
+            }
+        }
+        None
+    }
+
+    /// Emits the `jt`/`jf` ending block `start`. `fall` is the address of undecoded words when
+    /// the branch falls through into data.
+    fn emit_branch(
+        &mut self,
+        start: usize,
+        site: usize,
+        taken: Option<usize>,
+        fall: Result<usize, usize>,
+        stop: Option<usize>,
+        nodes: &mut Vec<Node>,
+    ) -> Option<usize> {
+        let condition = Condition {
+            site,
+            negate: false,
+        };
+        let (taken, fall) = match (taken, fall) {
+            (Some(taken), Ok(fall)) if taken == fall => return Some(taken),
+            (Some(taken), Ok(fall)) => (taken, fall),
+            (None, fall) => {
+                nodes.push(if_then(condition, vec![Node::Jump(site)]));
+                return match fall {
+                    Ok(fall) => Some(fall),
+                    Err(invalid) => {
+                        nodes.push(Node::Invalid(invalid));
+                        None
+                    }
+                };
+            }
+            (Some(taken), Err(invalid)) => {
+                nodes.push(if_then(condition.not(), vec![Node::Invalid(invalid)]));
+                return Some(taken);
+            }
+        };
+
+        // one arm only jumps somewhere else: `if (...) break;` and carry on with the other
+        for (target, other, condition) in [(taken, fall, condition), (fall, taken, condition.not())]
+        {
+            if Some(target) != stop
+                && let Some(node) = self.escape(target)
+            {
+                nodes.push(if_then(condition, vec![node]));
+                return Some(other);
+            }
+        }
+
+        let merge = self.post_dominators.get(&start).copied();
+        let arm_stop = merge.or(stop);
+        if let Some(merge) = merge {
+            self.stops.push(merge);
+        }
+        let then = self.sequence(taken, arm_stop);
+        let otherwise = self.sequence(fall, arm_stop);
+        if merge.is_some() {
+            self.stops.pop();
+        }
+        match (then.is_empty(), otherwise.is_empty()) {
+            (true, true) => {}
+            (true, false) => nodes.push(if_then(condition.not(), otherwise)),
+            _ => nodes.push(Node::If {
+                condition,
+                then,
+                otherwise,
+            }),
+        }
+        merge
+    }
+}
+
+fn if_then(condition: Condition, then: Vec<Node>) -> Node {
+    Node::If {
+        condition,
+        then,
+        otherwise: vec![],
+    }
+}
+
+/// The condition of an `if (...) break;`.
+fn breaks_if(node: &Node) -> Option<Condition> {
+    match node {
+        Node::If {
+            condition,
+            then,
+            otherwise,
+        } if then.as_slice() == [Node::Break] && otherwise.is_empty() => Some(*condition),
+        _ => None,
+    }
+}
+
+/// The condition of an `if (...) continue;`.
+fn continues_if(node: &Node) -> Option<Condition> {
+    match node {
+        Node::If {
+            condition,
+            then,
+            otherwise,
+        } if then.as_slice() == [Node::Continue] && otherwise.is_empty() => Some(*condition),
+        _ => None,
+    }
+}
+
+/// Whether a `continue` in `nodes` belongs to the loop containing them.
+fn continues(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Continue => true,
+        Node::If {
+            then, otherwise, ..
+        } => continues(then) || continues(otherwise),
+        _ => false,
+    })
+}
+
+fn goto_targets(nodes: &[Node], targets: &mut BTreeSet<usize>) {
+    for node in nodes {
+        match node {
+            Node::Goto(target) => {
+                targets.insert(*target);
+            }
+            Node::If {
+                then, otherwise, ..
+            } => {
+                goto_targets(then, targets);
+                goto_targets(otherwise, targets);
+            }
+            Node::Loop { body, .. } => goto_targets(body, targets),
+            _ => {}
+        }
+    }
+}
+
+/// Drops the labels no `goto` refers to, then joins straight-line code that only a dropped label
+/// separated, so that a run of `out`s across block leaders prints as one string.
+fn remove_labels(nodes: &mut Vec<Node>, targets: &BTreeSet<usize>) {
+    nodes.retain(|node| !matches!(node, Node::Label(label) if !targets.contains(label)));
+    nodes.dedup_by(|next, previous| match (previous, next) {
+        (Node::Code { to, .. }, Node::Code { from, to: end }) if *to == *from => {
+            *to = *end;
+            true
+        }
+        _ => false,
+    });
+    for node in nodes {
+        match node {
+            Node::If {
+                then, otherwise, ..
+            } => {
+                remove_labels(then, targets);
+                remove_labels(otherwise, targets);
+            }
+            Node::Loop { body, .. } => remove_labels(body, targets),
+            _ => {}
+        }
+    }
+}
+
+/// Finds the natural loop of every back edge, merging loops that share a header.
+///
+/// The follow block is the exit of the header if it has one, as in a `while`, otherwise the exit
+/// of a block jumping back to the header, as in a `do`/`while`, otherwise the lowest exit.
+fn natural_loops(
+    data: &[u16],
+    cfg: &Cfg,
+    entry: usize,
+    successors: &BTreeMap<usize, Vec<usize>>,
+) -> BTreeMap<usize, Option<usize>> {
+    let mut predecessors: BTreeMap<usize, Vec<usize>> =
+        successors.keys().map(|b| (*b, vec![])).collect();
+    for (from, targets) in successors {
+        for to in targets {
+            predecessors.get_mut(to).unwrap().push(*from);
+        }
+    }
+    let dominators = dominators(entry, successors, &predecessors);
+
+    let mut latches: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (from, targets) in successors {
+        for to in targets {
+            if dominators[from].contains(to) {
+                latches.entry(*to).or_default().push(*from);
+            }
+        }
+    }
+
+    let mut loops = BTreeMap::new();
+    for (header, latches) in latches {
+        let mut body = BTreeSet::from([header]);
+        let mut queue = latches.clone();
+        while let Some(block) = queue.pop() {
+            if body.insert(block) {
+                queue.extend(&predecessors[&block]);
+            }
+        }
+        let exit = |block: &usize| -> Option<usize> {
+            if !matches!(
+                Opcode::try_of(data[cfg.blocks[block].last]),
+                Some(Opcode::Jt | Opcode::Jf)
+            ) {
+                return None;
+            }
+            successors[block]
+                .iter()
+                .copied()
+                .find(|s| !body.contains(s))
+        };
+        let follow = exit(&header)
+            .or_else(|| latches.iter().find_map(exit))
+            .or_else(|| {
+                body.iter()
+                    .flat_map(|b| &successors[b])
+                    .copied()
+                    .filter(|s| !body.contains(s))
+                    .min()
+            });
+        loops.insert(header, follow);
+    }
+    loops
+}
+
+fn dominators(
+    entry: usize,
+    successors: &BTreeMap<usize, Vec<usize>>,
+    predecessors: &BTreeMap<usize, Vec<usize>>,
+) -> BTreeMap<usize, BTreeSet<usize>> {
+    let all: BTreeSet<usize> = successors.keys().copied().collect();
+    let mut dominators: BTreeMap<usize, BTreeSet<usize>> =
+        all.iter().map(|b| (*b, all.clone())).collect();
+    dominators.insert(entry, BTreeSet::from([entry]));
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in all.iter().filter(|b| **b != entry) {
+            let mut new = predecessors[block]
+                .iter()
+                .map(|p| dominators[p].clone())
+                .reduce(|a, b| &a & &b)
+                .unwrap_or_default();
+            new.insert(*block);
+            if new != dominators[block] {
+                dominators.insert(*block, new);
+                changed = true;
+            }
+        }
+    }
+    dominators
+}
+
+/// Immediate post-dominator of every block that can reach the end of the function.
+fn immediate_post_dominators(successors: &BTreeMap<usize, Vec<usize>>) -> BTreeMap<usize, usize> {
+    const EXIT: usize = usize::MAX;
+    let mut reaches_exit: BTreeSet<usize> = successors
+        .iter()
+        .filter(|(_, targets)| targets.is_empty())
+        .map(|(b, _)| *b)
+        .collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (block, targets) in successors {
+            if !reaches_exit.contains(block) && targets.iter().any(|t| reaches_exit.contains(t)) {
+                reaches_exit.insert(*block);
+                changed = true;
+            }
+        }
+    }
+
+    let mut all = reaches_exit.clone();
+    all.insert(EXIT);
+    let mut post_dominators: BTreeMap<usize, BTreeSet<usize>> =
+        reaches_exit.iter().map(|b| (*b, all.clone())).collect();
+    post_dominators.insert(EXIT, BTreeSet::from([EXIT]));
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in reaches_exit.iter().rev() {
+            let targets = &successors[block];
+            let mut new = if targets.is_empty() {
+                BTreeSet::from([EXIT])
+            } else {
+                targets
+                    .iter()
+                    .filter_map(|t| post_dominators.get(t))
+                    .cloned()
+                    .reduce(|a, b| &a & &b)
+                    .unwrap_or_default()
+            };
+            new.insert(*block);
+            if new != post_dominators[block] {
+                post_dominators.insert(*block, new);
+                changed = true;
+            }
+        }
+    }
+
+    let mut immediate = BTreeMap::new();
+    for block in &reaches_exit {
+        let closest = post_dominators[block]
+            .iter()
+            .filter(|d| *d != block)
+            .max_by_key(|d| post_dominators[d].len());
+        if let Some(closest) = closest
+            && *closest != EXIT
+        {
+            immediate.insert(*block, *closest);
+        }
+    }
+    immediate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::discovery::{discover, entry_points};
+    use crate::functions::CallGraph;
+
+    /// The nesting of ifs and loops, leaving out everything else.
+    fn shape(nodes: &[Node]) -> String {
+        nodes
+            .iter()
+            .filter_map(|node| match node {
+                Node::If {
+                    then, otherwise, ..
+                } if otherwise.is_empty() => Some(format!("if{{{}}}", shape(then))),
+                Node::If {
+                    then, otherwise, ..
+                } => Some(format!("if{{{}}}else{{{}}}", shape(then), shape(otherwise))),
+                Node::Loop {
+                    kind: LoopKind::While(_),
+                    body,
+                } => Some(format!("while{{{}}}", shape(body))),
+                Node::Loop { body, .. } => Some(format!("loop{{{}}}", shape(body))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn number_printer_has_two_while_loops_and_an_if_else() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        let code_map = discover(&image, &entry_points(&image));
+        let cfg = Cfg::build(&image, &code_map);
+        let call_graph = CallGraph::build(&image, &code_map, &cfg);
+        let function = &call_graph.functions[&2023];
+        let nodes = structure(&image, &cfg, &function.blocks, function.entry);
+        assert_eq!(shape(&nodes), "while{if{}while{}}if{while{}}else{}");
+        assert_eq!(nodes.last(), Some(&Node::Return));
+        assert!(!nodes.iter().any(|node| matches!(node, Node::Label(_))));
+    }
+
+    #[test]
+    fn code_a_dropped_label_separated_is_joined() {
+        let data = assemble(
+            "
+            call print
+            jmp middle
+        print:
+            out 'a'
+        middle:
+            out 'b'
+            ret
+            ",
+        )
+        .unwrap();
+        let code_map = discover(&data, &[0]);
+        let cfg = Cfg::build(&data, &code_map);
+        let nodes = structure(&data, &cfg, &cfg.function_blocks(4), 4);
+        assert_eq!(nodes, vec![Node::Code { from: 4, to: 8 }, Node::Return]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub struct Symbol {
+    pub name: String,
+    pub comment: Option<String>,
+}
+
+/// Names for addresses, read from a symbol file with one `addr name [comment]` entry per line.
+///
+/// Addresses are decimal or `0x` hex, names are identifiers that can be used as assembler and C
+/// labels, and the rest of the line is a free-form comment. Blank lines and lines starting with
+/// `#` are ignored.
+#[derive(Default)]
+pub struct Symbols {
+    pub by_address: BTreeMap<usize, Symbol>,
+}
+
+#[derive(Debug)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+/// Names a symbol cannot take because the generated C already uses them: C keywords, the library
+/// names the runtimes call, and the globals, functions and locals of the runtimes themselves.
+const RESERVED: &[&str] = &[
+    // C keywords
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "_Bool",
+    "_Complex",
+    "_Imaginary",
+    // C library
+    "NULL",
+    "EOF",
+    "stdin",
+    "stdout",
+    "stderr",
+    "exit",
+    "fprintf",
+    "fputs",
+    "getchar",
+    "putchar",
+    "memcpy",
+    "memset",
+    "realloc",
+    // runtimes of `dump-c` and `decompile`
+    "main",
+    "mem",
+    "original",
+    "translated",
+    "region_end",
+    "regions",
+    "stale",
+    "stale_count",
+    "registers",
+    "label_to_go",
+    "stack",
+    "stack_pointer",
+    "stack_capacity",
+    "push_stack",
+    "pop_stack",
+    "read_char",
+    "write_mem",
+    "is_stale",
+    "value_of",
+    "register_of",
+    "interpret",
+    "call",
+    "labels",
+    "pc",
+    "address",
+    "from",
+    "to",
+    "into",
+    "a",
+    "b",
+    "c",
+    "i",
+    "s",
+];
+
+impl Symbols {
+    pub fn parse(source: &str) -> Result<Symbols, SymbolError> {
+        let mut symbols = Symbols::default();
+        for (i, line) in source.lines().enumerate() {
+            symbols.add_line(line).map_err(|message| SymbolError {
+                line: i + 1,
+                message,
+            })?;
+        }
+        Ok(symbols)
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, Box<dyn std::error::Error>> {
+        let source = std::fs::read_to_string(path)?;
+        Symbols::parse(&source).map_err(|e| format!("{path}:{e}").into())
+    }
+
+    /// Writes the symbols back in the format [`Symbols::parse`] reads, one per line by address.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.to_text())
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (address, symbol) in &self.by_address {
+            text.push_str(&format!("{address} {}", symbol.name));
+            if let Some(comment) = &symbol.comment {
+                text.push_str(&format!(" {comment}"));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Adds the symbol of one `addr name [comment]` line. Blank lines and comments add nothing.
+    pub fn add_line(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let mut fields = line.splitn(3, char::is_whitespace);
+        let address = fields.next().unwrap();
+        let address = match address.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => address.parse(),
+        }
+        .map_err(|_| format!("invalid address `{address}`"))?;
+        let Some(name) = fields.next() else {
+            return Err(format!("missing name for address {address}"));
+        };
+        let comment = fields.next().map(str::trim).filter(|c| !c.is_empty());
+        self.insert(address, name, comment)
+    }
+
+    /// Adds or renames the symbol at `address`. Fails if the name is not a valid label, clashes
+    /// with a register, a generated `_N` label or `f_N` function, or a name in `RESERVED`, or is
+    /// already used for another address.
+    pub fn insert(
+        &mut self,
+        address: usize,
+        name: &str,
+        comment: Option<&str>,
+    ) -> Result<(), String> {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let register = name.len() == 2
+            && name.starts_with('r')
+            && name[1..].parse::<u8>().is_ok_and(|r| r < 8);
+        if !valid || register {
+            return Err(format!("`{name}` is not a valid symbol name"));
+        }
+        let generated = ["_", "f_"].iter().any(|prefix| {
+            name.strip_prefix(prefix)
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        });
+        if generated || RESERVED.contains(&name) {
+            return Err(format!("`{name}` is reserved in the generated code"));
+        }
+        if let Some((other, _)) = self
+            .by_address
+            .iter()
+            .find(|(a, s)| s.name == name && **a != address)
+        {
+            return Err(format!("`{name}` is already the name of {other}"));
+        }
+        self.by_address.insert(
+            address,
+            Symbol {
+                name: name.to_string(),
+                comment: comment.map(str::to_string),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, address: usize) -> Option<&Symbol> {
+        self.by_address.get(&address)
+    }
+
+    /// The symbol name of `address`, or `_address` if it has none.
+    pub fn label(&self, address: usize) -> String {
+        match self.get(address) {
+            Some(symbol) => symbol.name.clone(),
+            None => format!("_{address}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_symbols_parse_back_the_same() {
+        let mut symbols = Symbols::parse("# teleporter\n0x178b check  confirms r7\n\n").unwrap();
+        symbols.add_line("2023 print_number").unwrap();
+        assert_eq!(
+            symbols.to_text(),
+            "2023 print_number\n6027 check confirms r7\n"
+        );
+        let parsed = Symbols::parse(&symbols.to_text()).unwrap();
+        assert_eq!(parsed.label(6027), "check");
+        assert_eq!(parsed.label(6049), "_6049");
+        assert!(symbols.add_line("6049 check").is_err());
+        assert!(symbols.add_line("6049 r7").is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::Opcode;
+use crate::vm::{Stop, Vm, VmError};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `r1` the teleporter confirmation passes to the check routine at 6049, with `r0` as the level.
+const START_R1: u16 = 1;
+
+type Row = Box<[u16; 32768]>;
+
+/// Values of the teleporter check routine for one `r7`, computed bottom-up instead of by recursion.
+///
+/// The routine at 6049 computes
+///
+/// ```text
+/// f(0, b) = b + 1
+/// f(a, 0) = f(a - 1, r7)
+/// f(a, b) = f(a - 1, f(a, b - 1))
+/// ```
+///
+/// modulo 32768. Row `a` only depends on row `a - 1`, so filling each row from `b = 0` upwards
+/// gives every value up to the top row with no recursion at all. Rows 1 and 2 have closed forms,
+/// `r7 + 1 + b` and `2 * r7 + 1 + b * (r7 + 1)`, which leaves only the rows above them to walk.
+pub struct CheckTable {
+    /// `rows[a][b]` is `f(a, b)`.
+    rows: Vec<Row>,
+    r7: u16,
+}
+
+impl CheckTable {
+    /// A table for every `r0` up to `max_r0`. Call [`CheckTable::fill`] before reading it.
+    pub fn new(max_r0: u16) -> CheckTable {
+        let mut rows: Vec<Row> = (0..=max_r0).map(|_| Box::new([0; 32768])).collect();
+        for (b, value) in rows[0].iter_mut().enumerate() {
+            *value = (b as u16 + 1) & 32767;
+        }
+        CheckTable { rows, r7: 0 }
+    }
+
+    /// Recomputes the rows for `r7`, reusing their memory. Row 0 does not depend on it.
+    pub fn fill(&mut self, r7: u16) {
+        self.r7 = r7;
+        for a in 1..self.rows.len() {
+            let (below, rest) = self.rows.split_at_mut(a);
+            let (previous, row) = (&below[a - 1], &mut rest[0]);
+            match a {
+                1 | 2 => {
+                    let (start, step) = if a == 1 {
+                        (r7 + 1, 1)
+                    } else {
+                        (2 * r7 + 1, r7 + 1)
+                    };
+                    for (b, value) in row.iter_mut().enumerate() {
+                        *value = start.wrapping_add((b as u16).wrapping_mul(step)) & 32767;
+                    }
+                }
+                _ => {
+                    row[0] = previous[r7 as usize];
+                    for b in 1..row.len() {
+                        row[b] = previous[(row[b - 1] & 32767) as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    /// `f(r0, r1)` for the last filled `r7`. `r0` may be one above the top row, which is then
+    /// walked up to `r1` instead of read, so a search only needs the rows below its target.
+    pub fn get(&self, r0: u16, r1: u16) -> u16 {
+        if let Some(row) = self.rows.get(r0 as usize) {
+            return row[r1 as usize];
+        }
+        assert_eq!(r0 as usize, self.rows.len(), "row {r0} is not in the table");
+        let previous = &self.rows[r0 as usize - 1];
+        (0..r1).fold(previous[self.r7 as usize], |value, _| {
+            previous[value as usize]
+        })
+    }
+}
+
+/// Candidates a worker takes at a time. Small enough that cancelling wastes little work.
+const CHUNK: usize = 64;
+
+/// How often [`search_teleporter`] reports progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Every `r7` for which the check routine, started like the teleporter does with `target_r0`,
+/// returns `target_result`. The game wants `solve_teleporter(4, 6)`.
+pub fn solve_teleporter(target_r0: u16, target_result: u16) -> Vec<u16> {
+    let threads = thread::available_parallelism().map_or(1, usize::from);
+    search_teleporter(target_r0, target_result, threads, true, |_| {})
+}
+
+/// Every `r7` for which the check routine, started like the teleporter does with `target_r0`,
+/// returns `target_result`; the game wants `r0 = 4` and `6`. Tries them on `threads` workers,
+/// calling `progress` with the number of candidates checked so far now and then. Unless
+/// `exhaustive`, the search stops at the first hit and returns only the smallest matching `r7`.
+/// The result is sorted and does not depend on the thread count.
+pub fn search_teleporter(
+    target_r0: u16,
+    target_result: u16,
+    threads: usize,
+    exhaustive: bool,
+    progress: impl Fn(usize),
+) -> Vec<u16> {
+    if target_r0 == 0 {
+        let all = if START_R1 + 1 == target_result {
+            (1..32768).collect()
+        } else {
+            vec![]
+        };
+        return if exhaustive {
+            all
+        } else {
+            all.into_iter().take(1).collect()
+        };
+    }
+
+    // Chunks are handed out in increasing order, so once one starts past the smallest hit, every
+    // smaller candidate has been taken and the rest can be skipped.
+    let next = AtomicUsize::new(1);
+    let smallest_hit = AtomicU16::new(u16::MAX);
+    let checked = AtomicUsize::new(0);
+    let found = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut table = CheckTable::new(target_r0 - 1);
+                    loop {
+                        let start = next.fetch_add(CHUNK, Ordering::Relaxed);
+                        if start >= 32768
+                            || (!exhaustive
+                                && start > smallest_hit.load(Ordering::Relaxed) as usize)
+                        {
+                            break;
+                        }
+                        let end = (start + CHUNK).min(32768);
+                        for r7 in start as u16..end as u16 {
+                            table.fill(r7);
+                            if table.get(target_r0, START_R1) == target_result {
+                                found.lock().unwrap().push(r7);
+                                smallest_hit.fetch_min(r7, Ordering::Relaxed);
+                            }
+                        }
+                        checked.fetch_add(end - start, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        let mut reported = Instant::now();
+        while !workers.iter().all(|worker| worker.is_finished()) {
+            thread::sleep(Duration::from_millis(10));
+            if reported.elapsed() >= PROGRESS_INTERVAL {
+                progress(checked.load(Ordering::Relaxed));
+                reported = Instant::now();
+            }
+        }
+    });
+
+    let mut found = found.into_inner().unwrap();
+    found.sort();
+    if !exhaustive {
+        found.truncate(1);
+    }
+    found
+}
+
+/// Largest `r0`, `r1` and `r7` tried by [`verify`]. Past these the real routine takes too long.
+const VERIFY_MAX: (u16, u16, u16) = (4, 4, 5);
+
+/// Steps one run of the real routine may take before [`verify`] skips that case.
+const VERIFY_MAX_STEPS: u64 = 10_000_000;
+
+pub struct Mismatch {
+    pub r0: u16,
+    pub r1: u16,
+    pub r7: u16,
+    /// What [`CheckTable`] computes.
+    pub expected: u16,
+    /// What the routine in the image returns.
+    pub actual: u16,
+}
+
+pub struct Verification {
+    pub checked: usize,
+    /// Cases the routine did not finish within `VERIFY_MAX_STEPS`.
+    pub skipped: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Runs the check routine at `routine` in a sub-VM for every small `r0`, `r1` and `r7`, and
+/// compares what it leaves in `r0` with [`CheckTable`], so a wrong transcription shows up.
+pub fn verify(image: &[u16], routine: usize) -> Result<Verification, VmError> {
+    let (max_r0, max_r1, max_r7) = VERIFY_MAX;
+    let mut table = CheckTable::new(max_r0);
+    let mut verification = Verification {
+        checked: 0,
+        skipped: 0,
+        mismatches: vec![],
+    };
+    for r7 in 0..=max_r7 {
+        table.fill(r7);
+        for r0 in 0..=max_r0 {
+            for r1 in 0..=max_r1 {
+                let mut vm = Vm::new(image)?;
+                vm.pc = routine;
+                vm.registers[0] = r0;
+                vm.registers[1] = r1;
+                vm.registers[7] = r7;
+                if vm.run(VERIFY_MAX_STEPS)? != Stop::Halted {
+                    verification.skipped += 1;
+                    continue;
+                }
+                verification.checked += 1;
+                let (expected, actual) = (table.get(r0, r1), vm.registers[0]);
+                if expected != actual {
+                    verification.mismatches.push(Mismatch {
+                        r0,
+                        r1,
+                        r7,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+    }
+    Ok(verification)
+}
+
+/// How far before the check call [`find_confirmations`] looks for the `r7` test.
+const R7_TEST_WINDOW: usize = 64;
+
+/// The teleporter's confirmation: a call to the check routine whose result is compared with a
+/// constant and branched on, only reached when `r7` is set.
+pub struct Confirmation {
+    /// Address of the `jt` or `jf` on `r7` that guards the confirmation.
+    pub r7_test: usize,
+    /// Address of the `call`.
+    pub call: usize,
+    pub routine: usize,
+    /// Result the check has to return.
+    pub expected: u16,
+    /// Register the comparison is stored in.
+    pub flag: u16,
+}
+
+impl Confirmation {
+    /// Words that replace the `call` and the `eq` after it with their passing outcome,
+    /// `set r0 expected; set flag 1`, so the teleporter works without waiting for the check.
+    pub fn patch(&self) -> (usize, [u16; 6]) {
+        (
+            self.call,
+            [
+                Opcode::Set.code(),
+                32768,
+                self.expected,
+                Opcode::Set.code(),
+                self.flag,
+                1,
+            ],
+        )
+    }
+}
+
+/// Finds every `call ROUTINE; eq REG r0 VALUE; jt|jf REG TARGET` in `data` that shortly follows
+/// a `jt` or `jf` on `r7`, whether or not it decodes as code yet. Lookups that compare their
+/// result the same way are not guarded by `r7`, which tells them apart.
+pub fn find_confirmations(data: &[u16]) -> Vec<Confirmation> {
+    let is = |address: usize, opcode: Opcode| data[address] == opcode.code();
+    let is_register = |w: u16| (32768..32776).contains(&w);
+    let mut confirmations = vec![];
+    for call in 0..data.len().saturating_sub(8) {
+        let (routine, flag) = (data[call + 1], data[call + 3]);
+        if !is(call, Opcode::Call)
+            || routine >= 32768
+            || !is(call + 2, Opcode::Eq)
+            || !is_register(flag)
+        {
+            continue;
+        }
+        let expected = match (data[call + 4], data[call + 5]) {
+            (32768, value) | (value, 32768) if value < 32768 => value,
+            _ => continue,
+        };
+        if !(is(call + 6, Opcode::Jt) || is(call + 6, Opcode::Jf)) || data[call + 7] != flag {
+            continue;
+        }
+        let Some(r7_test) = (call.saturating_sub(R7_TEST_WINDOW)..call)
+            .rev()
+            .find(|t| (is(*t, Opcode::Jt) || is(*t, Opcode::Jf)) && data[t + 1] == 32775)
+        else {
+            continue;
+        };
+        confirmations.push(Confirmation {
+            r7_test,
+            call,
+            routine: routine as usize,
+            expected,
+            flag,
+        });
+    }
+    confirmations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Row `a` of the check routine for `r7`, filled straight from the recurrence.
+    fn row_by_recurrence(below: &[u16], r7: u16) -> Vec<u16> {
+        let mut row = vec![below[r7 as usize]];
+        for b in 1..32768 {
+            row.push(below[row[b - 1] as usize]);
+        }
+        row
+    }
+
+    #[test]
+    fn closed_form_rows_match_the_recurrence() {
+        let mut table = CheckTable::new(2);
+        for r7 in [0, 1, 2, 3, 1000, 25734, 32767] {
+            table.fill(r7);
+            let row0: Vec<u16> = (0..32768).map(|b| table.get(0, b)).collect();
+            let row1 = row_by_recurrence(&row0, r7);
+            let row2 = row_by_recurrence(&row1, r7);
+            for b in 0..32768 {
+                assert_eq!(table.get(1, b), row1[b as usize], "f(1, {b}) for r7 = {r7}");
+                assert_eq!(table.get(2, b), row2[b as usize], "f(2, {b}) for r7 = {r7}");
+            }
+        }
+    }
+
+    #[test]
+    fn one_level_deep_the_check_adds_r7_plus_one() {
+        // f(1, b) = b + r7 + 1, started with b = 1
+        assert_eq!(solve_teleporter(1, 10), vec![8]);
+        assert_eq!(solve_teleporter(1, 1), vec![32767]);
+    }
+
+    #[test]
+    fn thread_count_and_early_exit_agree_with_one_exhaustive_thread() {
+        for threads in [1, 4] {
+            assert_eq!(search_teleporter(1, 10, threads, true, |_| {}), vec![8]);
+            assert_eq!(search_teleporter(1, 10, threads, false, |_| {}), vec![8]);
+            // f(2, b) = f(2, b - 1) + r7 + 1 with f(2, 0) = 2 * r7 + 1, so f(2, 1) = 3 * r7 + 2
+            assert_eq!(search_teleporter(2, 20, threads, false, |_| {}), vec![6]);
+        }
+        // every r7 works when the check does not recurse
+        let all = search_teleporter(0, START_R1 + 1, 4, true, |_| {});
+        assert_eq!(all, (1..32768).collect::<Vec<u16>>());
+        assert_eq!(
+            search_teleporter(0, START_R1 + 1, 4, false, |_| {}),
+            vec![1]
+        );
+        assert!(search_teleporter(0, 5, 1, true, |_| {}).is_empty());
+    }
+
+    #[test]
+    fn known_answer_passes_the_check() {
+        let mut table = CheckTable::new(3);
+        table.fill(25734);
+        assert_eq!(table.get(4, START_R1), 6);
+        table.fill(25733);
+        assert_ne!(table.get(4, START_R1), 6);
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+/// Steps [`Vault::solve`] tries by default. The vault's shortest path takes 12.
+const DEFAULT_MAX_STEPS: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::North,
+        Direction::East,
+        Direction::South,
+        Direction::West,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::North => "north",
+            Direction::East => "east",
+            Direction::South => "south",
+            Direction::West => "west",
+        }
+    }
+
+    /// The neighbour of `position` this way in a grid of `rows` by `columns`, row 0 being north.
+    fn step(
+        &self,
+        (row, column): (usize, usize),
+        rows: usize,
+        columns: usize,
+    ) -> Option<(usize, usize)> {
+        match self {
+            Direction::North => Some((row.checked_sub(1)?, column)),
+            Direction::East => Some((row, column + 1)).filter(|_| column + 1 < columns),
+            Direction::South => Some((row + 1, column)).filter(|_| row + 1 < rows),
+            Direction::West => Some((row, column.checked_sub(1)?)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Add,
+    Multiply,
+    Subtract,
+}
+
+impl Operation {
+    /// `a` combined with `b`, or `None` on overflow.
+    pub fn execute(&self, a: i32, b: i32) -> Option<i32> {
+        match self {
+            Operation::Add => a.checked_add(b),
+            Operation::Multiply => a.checked_mul(b),
+            Operation::Subtract => a.checked_sub(b),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ValueOrOperation {
+    Value(i32),
+    Operation(Operation),
+}
+
+/// The orb puzzle in front of the vault: a grid of rooms with numbers and operators on their
+/// floors. Walking into an operator room remembers the operator, walking into a number room applies
+/// it to the orb's weight. The orb evaporates if its weight drops to zero or below, or if it is
+/// carried into a forbidden room, and the vault door only opens when the orb enters the target
+/// room with the target weight. The target room ends the walk either way.
+pub struct Vault {
+    /// Rows from north to south, each from west to east.
+    pub grid: Vec<Vec<ValueOrOperation>>,
+    /// `(row, column)` of the room the orb is picked up in.
+    pub start: (usize, usize),
+    pub start_value: i32,
+    pub target: (usize, usize),
+    pub target_value: i32,
+    pub forbidden: Vec<(usize, usize)>,
+    pub max_steps: usize,
+}
+
+/// A position in the walk: where the orb is, its weight and the operator waiting for a number.
+type State = ((usize, usize), i32, Option<Operation>);
+
+impl Vault {
+    /// A vault where the start room is forbidden to return to, as in the game.
+    pub fn new(
+        grid: Vec<Vec<ValueOrOperation>>,
+        start: (usize, usize),
+        start_value: i32,
+        target: (usize, usize),
+        target_value: i32,
+    ) -> Vault {
+        Vault {
+            grid,
+            start,
+            start_value,
+            target,
+            target_value,
+            forbidden: vec![start],
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+
+    /// The shortest walk that opens the vault, found breadth-first over every distinct state, or
+    /// `None` if there is none within `max_steps`. Ties go to the earliest direction in
+    /// [`Direction::ALL`] order, so the result is stable.
+    pub fn solve(&self) -> Option<Vec<Direction>> {
+        let (rows, columns) = (self.grid.len(), self.grid.first().map_or(0, Vec::len));
+        let start: State = (self.start, self.start_value, None);
+        // Every state reached, with the index of the state it was reached from and how.
+        let mut reached: Vec<(State, usize, Direction)> = vec![(start, 0, Direction::North)];
+        let mut seen: HashSet<State> = HashSet::from([start]);
+        let mut queue = VecDeque::from([(0, 0)]);
+        while let Some((index, steps)) = queue.pop_front() {
+            let (position, value, pending) = reached[index].0;
+            if position == self.target {
+                if value == self.target_value {
+                    return Some(self.path(&reached, index));
+                }
+                continue;
+            }
+            if steps == self.max_steps {
+                continue;
+            }
+            for direction in Direction::ALL {
+                let Some(next) = direction.step(position, rows, columns) else {
+                    continue;
+                };
+                if self.forbidden.contains(&next) {
+                    continue;
+                }
+                let state = match self.grid[next.0][next.1] {
+                    ValueOrOperation::Operation(operation) => (next, value, Some(operation)),
+                    ValueOrOperation::Value(v) => match pending {
+                        Some(operation) => match operation.execute(value, v) {
+                            Some(value) if value > 0 => (next, value, None),
+                            _ => continue,
+                        },
+                        None => (next, value, None),
+                    },
+                };
+                if seen.insert(state) {
+                    reached.push((state, index, direction));
+                    queue.push_back((reached.len() - 1, steps + 1));
+                }
+            }
+        }
+        None
+    }
+
+    fn path(&self, reached: &[(State, usize, Direction)], mut index: usize) -> Vec<Direction> {
+        let mut path = vec![];
+        while index != 0 {
+            let (_, parent, direction) = reached[index];
+            path.push(direction);
+            index = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Words in a room structure: name, description, exit names, exit targets and a handler.
+const ROOM_SIZE: usize = 5;
+
+/// Most exits a room can have, to tell exit lists from other length-prefixed data.
+const MAX_EXITS: usize = 16;
+
+/// A room of the game, as laid out in memory.
+struct Room {
+    description: String,
+    /// Exit names and the addresses of the rooms they lead to.
+    exits: Vec<(String, usize)>,
+}
+
+impl Vault {
+    /// Builds the vault from the rooms in booted memory: starts at the room with the orb's
+    /// pedestal, maps every room reachable from it by compass exits whose floor shows a number or
+    /// operator, and reads the start and target weights off the pedestal and the number carved into
+    /// the vault door.
+    pub fn from_memory(mem: &[u16]) -> Result<Vault, String> {
+        let rooms = find_rooms(mem);
+        let (&antechamber, start_room) = rooms
+            .iter()
+            .find(|(_, room)| room.description.contains("orb's pedestal"))
+            .ok_or(
+                "no room with the orb's pedestal; the rooms are only readable in booted memory",
+            )?;
+        let start_value =
+            quoted_number(&start_room.description).ok_or("no number on the orb's pedestal")?;
+
+        // Rooms by address with their (row, column), relative to the antechamber.
+        let mut positions: BTreeMap<usize, (i32, i32)> = BTreeMap::from([(antechamber, (0, 0))]);
+        let mut queue = VecDeque::from([antechamber]);
+        while let Some(address) = queue.pop_front() {
+            let (row, column) = positions[&address];
+            for (name, target) in &rooms[&address].exits {
+                let Some(direction) = Direction::ALL.into_iter().find(|d| d.as_str() == name)
+                else {
+                    continue;
+                };
+                if positions.contains_key(target)
+                    || rooms
+                        .get(target)
+                        .and_then(|r| floor(&r.description))
+                        .is_none()
+                {
+                    continue;
+                }
+                let next = match direction {
+                    Direction::North => (row - 1, column),
+                    Direction::East => (row, column + 1),
+                    Direction::South => (row + 1, column),
+                    Direction::West => (row, column - 1),
+                };
+                positions.insert(*target, next);
+                queue.push_back(*target);
+            }
+        }
+
+        let top = positions.values().map(|p| p.0).min().unwrap();
+        let left = positions.values().map(|p| p.1).min().unwrap();
+        let rows = (positions.values().map(|p| p.0).max().unwrap() - top + 1) as usize;
+        let columns = (positions.values().map(|p| p.1).max().unwrap() - left + 1) as usize;
+        let mut grid = vec![vec![None; columns]; rows];
+        let mut target = None;
+        for (address, (row, column)) in &positions {
+            let cell = ((row - top) as usize, (column - left) as usize);
+            let description = &rooms[address].description;
+            grid[cell.0][cell.1] = Some(match *address == antechamber {
+                true => ValueOrOperation::Value(start_value),
+                false => floor(description).unwrap(),
+            });
+            if description.contains("carved into it") {
+                let value = quoted_number(description).ok_or("no number on the vault door")?;
+                target = Some((cell, value));
+            }
+        }
+        let grid = grid
+            .into_iter()
+            .map(|row| row.into_iter().collect::<Option<Vec<_>>>())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("the vault rooms do not form a full grid")?;
+        let (target, target_value) = target.ok_or("no vault door among the vault rooms")?;
+        let start = ((-top) as usize, (-left) as usize);
+        Ok(Vault::new(grid, start, start_value, target, target_value))
+    }
+}
+
+/// Every room structure in memory by address: a text record for the name and another for the
+/// description, followed by matching lists of exit names and target rooms.
+fn find_rooms(mem: &[u16]) -> BTreeMap<usize, Room> {
+    let text = |address: u16| -> Option<String> {
+        let start = address as usize + 1;
+        let words = mem.get(start..start + *mem.get(address as usize)? as usize)?;
+        words
+            .iter()
+            .all(|w| matches!(w, 10 | 32..=126))
+            .then(|| words.iter().map(|w| *w as u8 as char).collect())
+    };
+    let list = |address: u16| -> Option<&[u16]> {
+        let length = *mem.get(address as usize)? as usize;
+        (length <= MAX_EXITS).then_some(())?;
+        mem.get(address as usize + 1..address as usize + 1 + length)
+    };
+    let mut rooms = BTreeMap::new();
+    for address in 0..mem.len().saturating_sub(ROOM_SIZE) {
+        let room = || -> Option<Room> {
+            text(mem[address])?;
+            let description = text(mem[address + 1])?;
+            let (names, targets) = (list(mem[address + 2])?, list(mem[address + 3])?);
+            if names.is_empty() || names.len() != targets.len() {
+                return None;
+            }
+            let names: Vec<String> = names.iter().map(|n| text(*n)).collect::<Option<_>>()?;
+            let exits = names
+                .into_iter()
+                .zip(targets.iter().map(|t| *t as usize))
+                .collect();
+            Some(Room { description, exits })
+        };
+        if let Some(room) = room() {
+            rooms.insert(address, room);
+        }
+    }
+    rooms
+}
+
+/// What the floor mosaic of a vault room shows: "the number '8'" or "a '*' symbol".
+fn floor(description: &str) -> Option<ValueOrOperation> {
+    let (_, mosaic) = description.split_once("mosaic depicting")?;
+    let (_, rest) = mosaic.split_once('\'')?;
+    let (shown, _) = rest.split_once('\'')?;
+    match shown {
+        "+" => Some(ValueOrOperation::Operation(Operation::Add)),
+        "-" => Some(ValueOrOperation::Operation(Operation::Subtract)),
+        "*" => Some(ValueOrOperation::Operation(Operation::Multiply)),
+        number => number.parse().ok().map(ValueOrOperation::Value),
+    }
+}
+
+/// The first number in quotes, like the '22' on the pedestal or the '30' on the door.
+fn quoted_number(description: &str) -> Option<i32> {
+    description
+        .split('\'')
+        .skip(1)
+        .step_by(2)
+        .find_map(|quoted| quoted.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Operation::*;
+    use ValueOrOperation::{Operation as Op, Value};
+
+    /// The vault antechamber of challenge.bin as read off its room descriptions, with the orb at
+    /// 22 in the southwest room and the vault door in the northeast one.
+    fn challenge_vault() -> Vault {
+        let grid = vec![
+            vec![Op(Multiply), Value(8), Op(Subtract), Value(1)],
+            vec![Value(4), Op(Multiply), Value(11), Op(Multiply)],
+            vec![Op(Add), Value(4), Op(Subtract), Value(18)],
+            vec![Value(22), Op(Subtract), Value(9), Op(Multiply)],
+        ];
+        Vault::new(grid, (3, 0), 22, (0, 3), 30)
+    }
+
+    /// Walks `path` by hand and returns the weight the orb arrives with.
+    fn replay(vault: &Vault, path: &[Direction]) -> i32 {
+        let (mut position, mut value, mut pending) = (vault.start, vault.start_value, None);
+        for direction in path {
+            position = direction
+                .step(position, vault.grid.len(), vault.grid[0].len())
+                .unwrap();
+            assert!(!vault.forbidden.contains(&position));
+            match vault.grid[position.0][position.1] {
+                Op(operation) => pending = Some(operation),
+                Value(v) => {
+                    value = pending
+                        .take()
+                        .map_or(value, |operation| operation.execute(value, v).unwrap());
+                    assert!(value > 0);
+                }
+            }
+        }
+        assert_eq!(position, vault.target);
+        value
+    }
+
+    #[test]
+    fn challenge_vault_opens_in_twelve_steps() {
+        let vault = challenge_vault();
+        let path = vault.solve().unwrap();
+        assert_eq!(path.len(), 12);
+        assert_eq!(replay(&vault, &path), 30);
+    }
+
+    #[test]
+    fn challenge_vault_path_is_stable() {
+        let names: Vec<&str> = challenge_vault()
+            .solve()
+            .unwrap()
+            .iter()
+            .map(Direction::as_str)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "north", "east", "east", "north", "west", "south", "east", "east", "west", "north",
+                "north", "east"
+            ]
+        );
+    }
+
+    #[test]
+    fn step_limit_below_shortest_path_finds_nothing() {
+        let mut vault = challenge_vault();
+        vault.max_steps = 11;
+        assert_eq!(vault.solve(), None);
+    }
+
+    #[test]
+    fn forbidden_rooms_are_avoided() {
+        let mut vault = challenge_vault();
+        let shortest = vault.solve().unwrap();
+        vault.forbidden.push((2, 1));
+        let path = vault.solve().unwrap();
+        assert_ne!(path, shortest);
+        assert_eq!(replay(&vault, &path), 30);
+    }
+
+    #[test]
+    fn booted_memory_gives_the_challenge_vault() {
+        let image = crate::load_image("challenge.bin").unwrap();
+        let vm = crate::snapshot::boot(&image, crate::snapshot::Until::Input, "").unwrap();
+        let vault = Vault::from_memory(&vm.mem).unwrap();
+        let expected = challenge_vault();
+        assert_eq!(vault.grid, expected.grid);
+        assert_eq!(
+            (vault.start, vault.start_value),
+            (expected.start, expected.start_value)
+        );
+        assert_eq!(
+            (vault.target, vault.target_value),
+            (expected.target, expected.target_value)
+        );
+    }
+}
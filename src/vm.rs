@@ -0,0 +1,248 @@
+use crate::Opcode;
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+
+/// Why [`Vm::run`] returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// `halt`, or `ret` with an empty stack.
+    Halted,
+    /// An `in` found the input queue empty. `pc` still points at the `in`.
+    NeedsInput,
+    /// `pc` reached a breakpoint. The instruction there has not run yet.
+    Breakpoint,
+    StepLimit,
+}
+
+#[derive(Debug)]
+pub struct VmError {
+    pub pc: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.pc, self.message)
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// A Synacor machine that can be stopped and inspected, for analyses that need live memory.
+pub struct Vm {
+    /// All 32768 words of memory.
+    pub mem: Vec<u16>,
+    pub registers: [u16; 8],
+    pub stack: Vec<u16>,
+    pub pc: usize,
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+    pub breakpoints: BTreeSet<usize>,
+    /// Instructions executed so far.
+    pub steps: u64,
+}
+
+impl Vm {
+    /// Loads `image` at address 0. Fails if it does not fit in memory.
+    pub fn new(image: &[u16]) -> Result<Vm, VmError> {
+        if image.len() > 32768 {
+            return Err(VmError {
+                pc: 0,
+                message: format!("image of {} words does not fit in 32768", image.len()),
+            });
+        }
+        let mut mem = vec![0; 32768];
+        mem[..image.len()].copy_from_slice(image);
+        Ok(Vm {
+            mem,
+            registers: [0; 8],
+            stack: vec![],
+            pc: 0,
+            input: VecDeque::new(),
+            output: vec![],
+            breakpoints: BTreeSet::new(),
+            steps: 0,
+        })
+    }
+
+    pub fn push_input(&mut self, text: &str) {
+        self.input.extend(text.bytes());
+    }
+
+    /// Runs until the machine stops, at most `max_steps` instructions. A breakpoint at the
+    /// starting `pc` does not stop it again, so a stopped machine can simply be resumed.
+    pub fn run(&mut self, max_steps: u64) -> Result<Stop, VmError> {
+        for i in 0..max_steps {
+            if i > 0 && self.breakpoints.contains(&self.pc) {
+                return Ok(Stop::Breakpoint);
+            }
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+        }
+        Ok(Stop::StepLimit)
+    }
+
+    /// Executes one instruction, or returns why it cannot.
+    pub fn step(&mut self) -> Result<Option<Stop>, VmError> {
+        let pc = self.pc;
+        let error = |message: String| VmError { pc, message };
+        let opcode = Opcode::try_of(self.mem[pc])
+            .ok_or_else(|| error(format!("unknown opcode {}", self.mem[pc])))?;
+        let arg = |i: usize| self.mem[(pc + 1 + i) & 32767];
+        let (a, b, c) = (arg(0), arg(1), arg(2));
+        let mut next = (pc + 1 + opcode.args()) & 32767;
+        match opcode {
+            Opcode::Halt => return Ok(Some(Stop::Halted)),
+            Opcode::Set => self.set(a, self.value(b)?)?,
+            Opcode::Push => self.stack.push(self.value(a)?),
+            Opcode::Pop => {
+                let value = self
+                    .stack
+                    .pop()
+                    .ok_or_else(|| error("pop on empty stack".to_string()))?;
+                self.set(a, value)?;
+            }
+            Opcode::Eq => self.set(a, (self.value(b)? == self.value(c)?) as u16)?,
+            Opcode::Gt => self.set(a, (self.value(b)? > self.value(c)?) as u16)?,
+            Opcode::Jmp => next = self.value(a)? as usize,
+            Opcode::Jt => {
+                if self.value(a)? != 0 {
+                    next = self.value(b)? as usize;
+                }
+            }
+            Opcode::Jf => {
+                if self.value(a)? == 0 {
+                    next = self.value(b)? as usize;
+                }
+            }
+            Opcode::Add => self.set(a, (self.value(b)? + self.value(c)?) % 32768)?,
+            Opcode::Mult => self.set(
+                a,
+                ((self.value(b)? as u32 * self.value(c)? as u32) % 32768) as u16,
+            )?,
+            Opcode::Mod => {
+                let divisor = self.value(c)?;
+                if divisor == 0 {
+                    return Err(error("mod by zero".to_string()));
+                }
+                self.set(a, self.value(b)? % divisor)?;
+            }
+            Opcode::And => self.set(a, self.value(b)? & self.value(c)?)?,
+            Opcode::Or => self.set(a, self.value(b)? | self.value(c)?)?,
+            Opcode::Not => self.set(a, !self.value(b)? & 32767)?,
+            Opcode::Rmem => self.set(a, self.mem[self.value(b)? as usize])?,
+            Opcode::Wmem => {
+                let address = self.value(a)? as usize;
+                self.mem[address] = self.value(b)?;
+            }
+            Opcode::Call => {
+                self.stack.push(next as u16);
+                next = self.value(a)? as usize;
+            }
+            Opcode::Ret => match self.stack.pop() {
+                Some(address) => next = address as usize,
+                None => return Ok(Some(Stop::Halted)),
+            },
+            Opcode::Out => {
+                let c = self.value(a)?;
+                let c = u8::try_from(c).map_err(|_| error(format!("cannot print {c}")))?;
+                self.output.push(c);
+            }
+            Opcode::In => match self.input.pop_front() {
+                Some(c) => self.set(a, c as u16)?,
+                None => return Ok(Some(Stop::NeedsInput)),
+            },
+            Opcode::Noop => {}
+        }
+        self.pc = next;
+        self.steps += 1;
+        Ok(None)
+    }
+
+    fn value(&self, a: u16) -> Result<u16, VmError> {
+        match a {
+            0..32768 => Ok(a),
+            32768..32776 => Ok(self.registers[a as usize - 32768]),
+            _ => Err(VmError {
+                pc: self.pc,
+                message: format!("invalid value {a}"),
+            }),
+        }
+    }
+
+    fn set(&mut self, a: u16, value: u16) -> Result<(), VmError> {
+        match a {
+            32768..32776 => {
+                self.registers[a as usize - 32768] = value;
+                Ok(())
+            }
+            _ => Err(VmError {
+                pc: self.pc,
+                message: format!("invalid register {a}"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const PROGRAM: &str = "
+        in r0
+        add r1, r0, 1
+        out r1
+        mult r2, 16384, 2
+        add r3, 32767, 2
+        call sub
+        out '!'
+        halt
+    sub:
+        push r3
+        pop r4
+        ret
+    ";
+
+    #[test]
+    fn waits_for_input_then_runs_to_halt() {
+        let mut vm = Vm::new(&assemble(PROGRAM).unwrap()).unwrap();
+        assert_eq!(vm.run(1000).unwrap(), Stop::NeedsInput);
+        assert_eq!(vm.pc, 0);
+        vm.push_input("A");
+        assert_eq!(vm.run(1000).unwrap(), Stop::Halted);
+        assert_eq!(vm.output, b"B!");
+        assert_eq!(vm.registers, [65, 66, 0, 1, 1, 0, 0, 0]);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn stops_at_breakpoints_and_resumes_past_them() {
+        let data = assemble(PROGRAM).unwrap();
+        let mut vm = Vm::new(&data).unwrap();
+        vm.push_input("A");
+        let sub = data.len() - 5;
+        vm.breakpoints.insert(sub);
+        assert_eq!(vm.run(1000).unwrap(), Stop::Breakpoint);
+        assert_eq!(vm.pc, sub);
+        assert_eq!(vm.output, b"B");
+        assert_eq!(vm.run(2).unwrap(), Stop::StepLimit);
+        assert_eq!(vm.run(1000).unwrap(), Stop::Halted);
+        assert_eq!(vm.steps, 10);
+    }
+
+    #[test]
+    fn errors_name_the_failing_instruction() {
+        let mut vm = Vm::new(&assemble("noop\npop r0").unwrap()).unwrap();
+        let error = vm.run(1000).unwrap_err();
+        assert_eq!(
+            (error.pc, error.message.as_str()),
+            (1, "pop on empty stack")
+        );
+        let mut vm = Vm::new(&assemble("out 256").unwrap()).unwrap();
+        assert_eq!(vm.run(1000).unwrap_err().message, "cannot print 256");
+        assert!(Vm::new(&vec![0; 32769]).is_err());
+        assert!(Vm::new(&vec![0; 32768]).is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::Opcode;
+use crate::disasm::format_instruction;
+use crate::discovery::{discover, entry_points, sweep_instructions};
+use crate::symbols::Symbols;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// Literal target of `jmp`, `jt` or `jf`.
+    Jump,
+    /// Literal target of `call`.
+    Call,
+    /// Literal address of `rmem`.
+    Read,
+    /// Literal address of `wmem`.
+    Write,
+    /// Literal value of `set` or `push`, likely a pointer when it matches a code or table address.
+    Constant,
+}
+
+impl ReferenceKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ReferenceKind::Jump => "jump",
+            ReferenceKind::Call => "call",
+            ReferenceKind::Read => "read",
+            ReferenceKind::Write => "write",
+            ReferenceKind::Constant => "constant",
+        }
+    }
+}
+
+pub struct Reference {
+    /// Address of the referring instruction.
+    pub site: usize,
+    pub opcode: Opcode,
+    pub kind: ReferenceKind,
+}
+
+/// Every literal address operand of the decoded instructions, indexed by the address it names.
+pub struct Xrefs {
+    pub by_target: BTreeMap<usize, Vec<Reference>>,
+}
+
+impl Xrefs {
+    /// Decodes the image like the C backend does: everything reachable from address 0 and from
+    /// any literal `call` target, plus a linear sweep over the rest. Code that is still encrypted
+    /// in `data` is missed, so pass a memory snapshot to cover it.
+    pub fn build(data: &[u16]) -> Xrefs {
+        let code_map = discover(data, &entry_points(data));
+        let mut by_target: BTreeMap<usize, Vec<Reference>> = BTreeMap::new();
+        for (site, opcode) in sweep_instructions(data, &code_map) {
+            let (kind, operand) = match opcode {
+                Opcode::Jmp => (ReferenceKind::Jump, data[site + 1]),
+                Opcode::Jt | Opcode::Jf => (ReferenceKind::Jump, data[site + 2]),
+                Opcode::Call => (ReferenceKind::Call, data[site + 1]),
+                Opcode::Rmem => (ReferenceKind::Read, data[site + 2]),
+                Opcode::Wmem => (ReferenceKind::Write, data[site + 1]),
+                Opcode::Set => (ReferenceKind::Constant, data[site + 2]),
+                Opcode::Push => (ReferenceKind::Constant, data[site + 1]),
+                _ => continue,
+            };
+            if operand < 32768 {
+                by_target
+                    .entry(operand as usize)
+                    .or_default()
+                    .push(Reference { site, opcode, kind });
+            }
+        }
+        Xrefs { by_target }
+    }
+
+    /// Lists the references to `only`, or to every referenced address when it is `None`.
+    pub fn to_text(
+        &self,
+        data: &[u16],
+        symbols: &Symbols,
+        only: Option<usize>,
+    ) -> Result<String, std::fmt::Error> {
+        let mut writer = String::new();
+        let targets: Vec<usize> = match only {
+            Some(address) => vec![address],
+            None => self.by_target.keys().copied().collect(),
+        };
+        for target in targets {
+            match symbols.get(target) {
+                Some(symbol) => writeln!(writer, "{target} {}:", symbol.name)?,
+                None => writeln!(writer, "{target}:")?,
+            }
+            for reference in self.by_target.get(&target).into_iter().flatten() {
+                writeln!(
+                    writer,
+                    "  {:5}: {:<8} {}",
+                    reference.site,
+                    reference.kind.name(),
+                    format_instruction(data, reference.site, reference.opcode, symbols)
+                )?;
+            }
+        }
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    #[test]
+    fn literal_operands_are_indexed_by_the_address_they_name() {
+        let data = assemble(
+            "
+            call sub
+            set r0, table
+            rmem r1, table
+            wmem table, r1
+            jt r1, end
+            push sub
+            call r0
+        end:
+            halt
+        sub:
+            ret
+        table:
+            .word 7
+            ",
+        )
+        .unwrap();
+        let xrefs = Xrefs::build(&data);
+        let references = |target: usize| -> Vec<(usize, ReferenceKind)> {
+            xrefs.by_target[&target]
+                .iter()
+                .map(|r| (r.site, r.kind))
+                .collect()
+        };
+        assert_eq!(
+            xrefs.by_target.keys().copied().collect::<Vec<_>>(),
+            vec![18, 19, 20]
+        );
+        assert_eq!(references(18), vec![(11, ReferenceKind::Jump)]);
+        assert_eq!(
+            references(19),
+            vec![(0, ReferenceKind::Call), (14, ReferenceKind::Constant)]
+        );
+        assert_eq!(
+            references(20),
+            vec![
+                (2, ReferenceKind::Constant),
+                (5, ReferenceKind::Read),
+                (8, ReferenceKind::Write)
+            ]
+        );
+
+        let symbols = Symbols::parse("20 table").unwrap();
+        assert_eq!(
+            xrefs
+                .to_text(&data, &symbols, Some(20))
+                .unwrap()
+                .lines()
+                .next(),
+            Some("20 table:")
+        );
+    }
+}